@@ -0,0 +1,53 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use kvs::{random_workload, KvStore, Op};
+use tempfile::TempDir;
+
+fn apply(store: &mut KvStore, ops: &[Op]) {
+    for op in ops {
+        match op {
+            Op::Set(k, v) => store.set(k.clone(), v.clone()).unwrap(),
+            Op::Rm(k) => store.remove(k.clone()).unwrap(),
+            _ => (),
+        }
+    }
+}
+
+fn write_bench(c: &mut Criterion) {
+    let ops = random_workload(1000, 42);
+    c.bench_function("write", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                (temp_dir, store)
+            },
+            |(_temp_dir, mut store)| apply(&mut store, &ops),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+fn read_bench(c: &mut Criterion) {
+    let ops = random_workload(1000, 42);
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    apply(&mut store, &ops);
+    let keys: Vec<String> = ops
+        .iter()
+        .filter_map(|op| match op {
+            Op::Set(k, _) => Some(k.clone()),
+            _ => None,
+        })
+        .collect();
+
+    c.bench_function("read", |b| {
+        b.iter(|| {
+            for key in &keys {
+                store.get(key.clone()).unwrap();
+            }
+        })
+    });
+}
+
+criterion_group!(benches, write_bench, read_bench);
+criterion_main!(benches);
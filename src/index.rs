@@ -0,0 +1,132 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound
+};
+
+use crate::{KvError, Result};
+
+/// The map behind a store's index. Set with `KvStoreBuilder::index_kind`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// A `BTreeMap`, kept in key order.
+    #[default]
+    Ordered,
+    /// A `HashMap`, for faster lookups where keys are never scanned by
+    /// range. `get_range`, `list_page` and `remove_prefix` fail with
+    /// `UnorderedIndexError`; whole-store walks such as `iter` and
+    /// compaction still run in key order by sorting the keys first.
+    Hash,
+}
+
+// offset and length of the latest record of a key
+pub(crate) type Entry = (u64, u32);
+
+pub(crate) enum Index {
+    Ordered(BTreeMap<String, Entry>),
+    Hash(HashMap<String, Entry>),
+}
+
+impl Index {
+    pub(crate) fn new(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Ordered => Index::Ordered(BTreeMap::new()),
+            IndexKind::Hash => Index::Hash(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn from_entries(kind: IndexKind, entries: impl IntoIterator<Item = (String, Entry)>) -> Self {
+        match kind {
+            IndexKind::Ordered => Index::Ordered(entries.into_iter().collect()),
+            IndexKind::Hash => Index::Hash(entries.into_iter().collect()),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> IndexKind {
+        match self {
+            Index::Ordered(_) => IndexKind::Ordered,
+            Index::Hash(_) => IndexKind::Hash,
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Entry> {
+        match self {
+            Index::Ordered(map) => map.get(key),
+            Index::Hash(map) => map.get(key),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub(crate) fn insert(&mut self, key: String, entry: Entry) -> Option<Entry> {
+        match self {
+            Index::Ordered(map) => map.insert(key, entry),
+            Index::Hash(map) => map.insert(key, entry),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<Entry> {
+        match self {
+            Index::Ordered(map) => map.remove(key),
+            Index::Hash(map) => map.remove(key),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Index::Ordered(map) => map.len(),
+            Index::Hash(map) => map.len(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = Index::new(self.kind());
+    }
+
+    // entries in key order; a hash index sorts a copy of its keys first
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Entry)> + '_> {
+        match self {
+            Index::Ordered(map) => Box::new(map.iter()),
+            Index::Hash(map) => {
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+                Box::new(entries.into_iter())
+            }
+        }
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    // entries with keys between `start` and `end`, in key order; an empty
+    // or inverted range yields none
+    pub(crate) fn range<'a>(&'a self, start: Bound<&str>, end: Bound<&str>) -> Result<Box<dyn Iterator<Item = (&'a String, &'a Entry)> + 'a>> {
+        let Index::Ordered(map) = self else {
+            return Err(KvError::UnorderedIndexError);
+        };
+        let empty = match (start, end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s >= e,
+            _ => false
+        };
+        if empty {
+            // BTreeMap::range panics on these
+            return Ok(Box::new(std::iter::empty()));
+        }
+        Ok(Box::new(map.range::<str, _>((start, end))))
+    }
+
+    // moves the entries into a freshly allocated map
+    pub(crate) fn shrink(&mut self) {
+        *self = match std::mem::replace(self, Index::new(self.kind())) {
+            Index::Ordered(map) => Index::Ordered(map.into_iter().collect()),
+            Index::Hash(map) => Index::Hash(map.into_iter().collect()),
+        };
+    }
+}
@@ -0,0 +1,56 @@
+// separates the prefix and components of a composite key
+const SEPARATOR: char = ':';
+// makes the next character part of the component
+const ESCAPE: char = '\\';
+
+/// Builds a key from a namespace prefix and components, escaping every
+/// separator and escape character inside the components, so two different
+/// lists of components never produce the same key. `parse_key` splits it
+/// back. Usually written with the `key!` macro.
+///
+/// Panics if `prefix` is empty or contains `:` or `\`; prefixes are meant
+/// to be fixed names rather than user input.
+pub fn composite_key(prefix: &str, components: &[&str]) -> String {
+    assert!(
+        !prefix.is_empty() && !prefix.contains([SEPARATOR, ESCAPE]),
+        "key prefix {prefix:?} must be non-empty and free of ':' and '\\'"
+    );
+    let mut key = prefix.to_owned();
+    for component in components {
+        key.push(SEPARATOR);
+        for c in component.chars() {
+            if c == SEPARATOR || c == ESCAPE {
+                key.push(ESCAPE);
+            }
+            key.push(c);
+        }
+    }
+    key
+}
+
+/// Splits a key built by `composite_key` into its prefix and components.
+/// Returns `None` for a key that ends in a lone escape character, which
+/// `composite_key` never builds.
+pub fn parse_key(key: &str) -> Option<(String, Vec<String>)> {
+    let mut parts = vec![String::new()];
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ESCAPE => parts.last_mut().expect("never empty").push(chars.next()?),
+            SEPARATOR => parts.push(String::new()),
+            c => parts.last_mut().expect("never empty").push(c),
+        }
+    }
+    let prefix = parts.remove(0);
+    Some((prefix, parts))
+}
+
+/// Builds a composite key from a prefix and any number of `Display`
+/// components, e.g. `key!("user", id)` or `key!("order", user, n)`. See
+/// `composite_key`.
+#[macro_export]
+macro_rules! key {
+    ($prefix:expr $(, $component:expr)* $(,)?) => {
+        $crate::composite_key($prefix, &[$(&$component.to_string()),*])
+    };
+}
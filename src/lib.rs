@@ -1,10 +1,35 @@
 use std::{
-    collections::BTreeMap, fmt, fs::{self, File}, io::{self, BufRead, BufReader, Seek, Write},
-    path
+    borrow::Cow, collections::{btree_map::Entry, BTreeMap, BTreeSet}, fmt, fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write},
+    ops::Bound, path, sync::{atomic::{AtomicU64, Ordering}, mpsc, Arc, Mutex, MutexGuard}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}
 };
 
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{read::GzDecoder, write::GzEncoder};
 use serde::{Serialize, Deserialize};
-use serde_json;
+use sha2::{Digest, Sha256};
+
+use index::Index;
+use values::ValueIndex;
+
+mod index;
+mod keys;
+mod manager;
+mod shard;
+mod shared;
+mod values;
+pub use index::IndexKind;
+pub use keys::{composite_key, parse_key};
+pub use manager::StoreManager;
+pub use shard::{ConsistentHashRouter, Shardable, ShardedKvStore};
+pub use shared::{KeyGuard, SharedKvStore};
+
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "bench")]
+mod workload;
+#[cfg(feature = "bench")]
+pub use workload::random_workload;
 
 
 pub type Result<T> = std::result::Result<T, KvError>;
@@ -13,8 +38,20 @@ pub type Result<T> = std::result::Result<T, KvError>;
 pub enum KvError {
     // my errors
     InvalidCommandError,
-    InvalidKeyError,
     KeyNotFoundError,
+    NotADirectoryError(path::PathBuf),
+    CorruptValueError,
+    OffsetGoneError(u64),
+    LockedError,
+    DanglingIndexError(u64),
+    NonUtf8RecordError { offset: u64 },
+    ReadOnlyError,
+    InvalidStoreNameError(String),
+    ValidationError(String),
+    UnorderedIndexError,
+    DuplicateKeyError(String),
+    LogReplacedError,
+    RecordTooLargeError { len: u64, max: u64 },
     // embedded errors
     IoError(std::io::Error),
     SerdeJsonError(serde_json::Error)
@@ -32,11 +69,47 @@ impl fmt::Display for KvError {
             Self::InvalidCommandError => {
                 write!(f, "Invalid command found at offset for get operation")
             },
-            Self::InvalidKeyError => {
-                write!(f, "Invalid key found at offset for get operation")
-            },
             Self::KeyNotFoundError => {
                 write!(f, "Key not found")
+            },
+            Self::NotADirectoryError(p) => {
+                write!(f, "Store path {} is not a directory", p.display())
+            },
+            Self::CorruptValueError => {
+                write!(f, "Stored value could not be decoded")
+            },
+            Self::OffsetGoneError(offset) => {
+                write!(f, "Log offset {offset} is no longer valid")
+            },
+            Self::LockedError => {
+                write!(f, "Store is locked by another handle")
+            },
+            Self::DanglingIndexError(offset) => {
+                write!(f, "Index points at a record for another key at offset {offset}")
+            },
+            Self::ReadOnlyError => {
+                write!(f, "Store was opened read-only")
+            },
+            Self::NonUtf8RecordError { offset } => {
+                write!(f, "Record at offset {offset} is not valid UTF-8")
+            },
+            Self::InvalidStoreNameError(name) => {
+                write!(f, "Store name {name:?} is not a plain directory name")
+            },
+            Self::ValidationError(reason) => {
+                write!(f, "Value rejected by validator: {reason}")
+            },
+            Self::UnorderedIndexError => {
+                write!(f, "Operation needs an ordered index")
+            },
+            Self::DuplicateKeyError(key) => {
+                write!(f, "Key {key:?} appears more than once in the input")
+            },
+            Self::LogReplacedError => {
+                write!(f, "Log was replaced since the store was opened")
+            },
+            Self::RecordTooLargeError { len, max } => {
+                write!(f, "Record of {len} bytes does not fit in a log capped at {max} bytes")
             }
         }
     }
@@ -54,159 +127,2593 @@ impl From<serde_json::Error> for KvError {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Op {
     Set(String,String),
     Rm(String),
     Get(String),
+    /// A `Set` carrying `meta`, which describes how the value is encoded and
+    /// when it was written. All sets are written in this form.
+    SetWithMeta { key: String, value: String, #[serde(default)] meta: RecordMeta },
 }
 
+/// Per-record flags stored alongside a `SetWithMeta` value.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordMeta {
+    #[serde(default)]
+    pub compression: Compression,
+    /// When the record was written, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// The value is kept in the blob file named by the record's value
+    /// rather than in the log. See `KvStoreBuilder::blob_threshold`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub blob: bool,
+}
 
-pub struct KvStore {
-    index: BTreeMap<String,u64>,
-    log_file: path::PathBuf,
-    log_size: u64
+/// Codec applied to values before they are written. Keys are never compressed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
 }
 
-impl KvStore {
-    pub fn open(path: impl Into<path::PathBuf>) -> Result<KvStore> {
-        let mut dirpath = path.into().clone();
-        dirpath.push("store");
+impl Compression {
+    // compresses and base64-encodes the value so it stays valid json
+    fn encode(self, value: &str) -> Result<String> {
+        let bytes = match self {
+            Self::None => return Ok(value.to_owned()),
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(value.as_bytes())?;
+                encoder.finish()?
+            },
+            Self::Zstd => zstd::encode_all(value.as_bytes(), 0)?
+        };
+        Ok(STANDARD.encode(bytes))
+    }
 
-        let kv_store = KvStore{index: BTreeMap::new(), log_file: dirpath, log_size: 0};
+    fn decode(self, value: String) -> Result<String> {
+        let raw = match self {
+            Self::None => return Ok(value),
+            Self::Gzip => {
+                let mut raw = Vec::new();
+                GzDecoder::new(decode_base64(&value)?.as_slice()).read_to_end(&mut raw)?;
+                raw
+            },
+            Self::Zstd => zstd::decode_all(decode_base64(&value)?.as_slice())?
+        };
+        String::from_utf8(raw).map_err(|_| KvError::CorruptValueError)
+    }
+}
 
-        if let Err(e) = File::open(&kv_store.log_file) {
-            if let io::ErrorKind::NotFound = e.kind(){
-                File::create(&kv_store.log_file)?;
-            } else {
-                return Err(KvError::IoError(e));
-            }
-        }
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+    STANDARD.decode(value).map_err(|_| KvError::CorruptValueError)
+}
 
-        kv_store.construct_index()
+/// What a store knows about a key, as returned by `KvStore::status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyStatus {
+    Present(String),
+    /// The key was removed and its tombstone is still in the log.
+    Deleted,
+    NeverExisted,
+}
+
+/// What `KvStore::bulk_load` does with a key given more than once. Set with
+/// `KvStoreBuilder::on_duplicate`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OnDuplicate {
+    /// Keep the value given last, as replaying the same sets would.
+    #[default]
+    LastWins,
+    /// Fail with `DuplicateKeyError`, writing nothing.
+    Error,
+}
+
+/// What a compaction changed, as returned by `KvStore::compact`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionSummary {
+    pub records_before: usize,
+    pub records_after: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub duration: Duration,
+}
+
+/// Outcome of `KvStore::repair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Records that could not be parsed and were left out.
+    pub dropped: usize,
+    /// Keys live in the rewritten log.
+    pub live_keys: usize,
+}
+
+/// A summary of a store's size, as returned by `KvStore::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub live_keys: usize,
+    pub log_size: u64,
+    /// Log bytes taken by overwritten values and tombstones, which the next
+    /// compaction would reclaim.
+    pub dead_bytes: u64,
+    /// Compactions run by this handle, not over the life of the store.
+    pub compaction_count: u64,
+}
+
+/// Receives events from every compaction of a store, e.g. for metrics.
+/// Set with `KvStoreBuilder::compaction_observer`; both methods default to
+/// doing nothing.
+pub trait CompactionObserver: Send + Sync {
+    /// Called before the live records are read, with the number of keys in
+    /// the index.
+    fn on_start(&self, _live_keys: usize) {}
+
+    /// Called once the compacted log has replaced the old one.
+    fn on_finish(&self, _bytes_before: u64, _bytes_after: u64, _duration: Duration) {}
+}
+
+// lets callers keep a handle on the observer they hand to the builder
+impl<T: CompactionObserver + ?Sized> CompactionObserver for Arc<T> {
+    fn on_start(&self, live_keys: usize) {
+        (**self).on_start(live_keys)
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        // self.print_index();
-        //operation
-        let op = Op::Set(key, value);
+    fn on_finish(&self, bytes_before: u64, bytes_after: u64, duration: Duration) {
+        (**self).on_finish(bytes_before, bytes_after, duration)
+    }
+}
 
-        // serialize operation
-        let mut serialized_op = serde_json::to_vec(& op)?;
-        serialized_op.extend_from_slice("\n".as_bytes());
-        let mut file = fs::OpenOptions::new().append(true).create(true).open(&self.log_file)?;
-        let offset = file.seek(io::SeekFrom::End(0))?;
+// the observer set on a builder; Debug so the builder can keep deriving it
+#[derive(Clone)]
+struct ObserverHandle(Arc<dyn CompactionObserver>);
+
+impl fmt::Debug for ObserverHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionObserver")
+    }
+}
+
+type ValidatorFn = dyn Fn(&str) -> std::result::Result<(), String> + Send + Sync;
+
+// a user supplied check run on every value before it is written
+#[derive(Clone)]
+struct ValueValidator(Arc<ValidatorFn>);
+
+impl fmt::Debug for ValueValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueValidator")
+    }
+}
+
+type KeyFilterFn = dyn Fn(&str) -> bool + Send + Sync;
+
+// a user supplied predicate choosing the keys a partial open indexes
+#[derive(Clone)]
+struct KeyFilter(Arc<KeyFilterFn>);
+
+impl fmt::Debug for KeyFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyFilter")
+    }
+}
 
-        self.log_size += serialized_op.len() as u64;
-        file.write(& serialized_op.as_slice())?;
-        file.flush()?;
+/// Options for opening a `KvStore`. Obtained from `KvStore::builder()`.
+#[derive(Debug, Clone)]
+pub struct KvStoreBuilder {
+    compression: Compression,
+    read_only: bool,
+    strict_remove: bool,
+    file_prefix: Option<String>,
+    max_total_bytes: Option<u64>,
+    flush_interval: Option<Duration>,
+    flush_every: usize,
+    skip_redundant_writes: bool,
+    io_retries: usize,
+    preallocate_bytes: u64,
+    compaction_threshold: u64,
+    compaction_cooldown: Option<Duration>,
+    value_validator: Option<ValueValidator>,
+    keep_versions: usize,
+    compaction_observer: Option<ObserverHandle>,
+    compact_on_close: bool,
+    key_filter: Option<KeyFilter>,
+    index_kind: IndexKind,
+    on_duplicate: OnDuplicate,
+    blob_threshold: Option<usize>,
+    value_index: bool,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    #[cfg(unix)]
+    file_mode: Option<u32>,
+}
 
-        // update index
-        if let Op::Set(k,_) = op {
-            self.index.insert(k, offset);
+impl Default for KvStoreBuilder {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            read_only: false,
+            strict_remove: true,
+            file_prefix: None,
+            max_total_bytes: None,
+            flush_interval: None,
+            flush_every: 0,
+            skip_redundant_writes: false,
+            io_retries: 0,
+            preallocate_bytes: 0,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compaction_cooldown: None,
+            value_validator: None,
+            keep_versions: 1,
+            compaction_observer: None,
+            compact_on_close: false,
+            key_filter: None,
+            index_kind: IndexKind::Ordered,
+            on_duplicate: OnDuplicate::LastWins,
+            blob_threshold: None,
+            value_index: false,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+            #[cfg(unix)]
+            file_mode: None,
         }
+    }
+}
 
-        self.compact()?;
-        Ok(())
+impl KvStoreBuilder {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(offset) = self.index.get(&key) {
-            let mut file = File::open(& self.log_file)?;
-            file.seek(io::SeekFrom::Start(*offset))?;
+    /// Compress values written by this handle. Records written with a
+    /// different setting stay readable.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
 
-            let mut buf_reader = io::BufReader::new( &mut file);
+    /// Open without creating or writing any file, e.g. on a read-only
+    /// mount. Writes fail with `ReadOnlyError`. Read-only handles share a
+    /// lock on the log, so any number of them can be open at once, and a
+    /// writable open fails with `LockedError` while one is. Read-only
+    /// handles can still be opened next to an already open writer.
+    ///
+    /// This is how other processes should read a store while its writer
+    /// runs. The writer may compact at any time, which swaps in a new log
+    /// file; a read-only handle notices the swap on its next read, which
+    /// fails with `LogReplacedError`, and `KvStore::refresh` then moves it
+    /// to the new file. `SharedKvStore` refreshes and retries on its own.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
 
-            let mut line = String::new();
-            buf_reader.read_line(&mut line)?;
+    /// When true (the default) removing a missing key fails with
+    /// `KeyNotFoundError`; when false it is a successful no-op.
+    pub fn strict_remove(mut self, strict_remove: bool) -> Self {
+        self.strict_remove = strict_remove;
+        self
+    }
 
-            let op = serde_json::from_slice::<Op>(line.as_bytes())?;
+    /// Namespace every file of the store as `<prefix>-<name>`, so several
+    /// stores can share one directory. Only files with the prefix are read.
+    pub fn file_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.file_prefix = Some(prefix.into());
+        self
+    }
 
-            if let Op::Set(k, v) = op {
-                if k == key {
-                    return Ok(Some(v))
-                } else {
-                    return Err(KvError::InvalidKeyError)
-                }
-            } else {
-                Err(KvError::InvalidCommandError)
-            }
-        } else {
-            Ok(None)
+    /// Bound the log to `max` bytes, for cache-like use. When a write takes
+    /// the log past the cap it is compacted, and if the live data alone is
+    /// still over the cap the least recently written keys are dropped until
+    /// it fits. A write whose record alone is over the cap fails with
+    /// `RecordTooLargeError` instead, writing nothing.
+    pub fn max_total_bytes(mut self, max: Option<u64>) -> Self {
+        self.max_total_bytes = max;
+        self
+    }
+
+    /// Buffer writes and flush them from a background thread every
+    /// `interval` instead of after each write. Up to one interval of writes
+    /// can be lost on a crash. Reads through this handle always see buffered
+    /// writes; other handles see them once flushed.
+    pub fn flush_interval(mut self, interval: Option<Duration>) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Flush buffered writes once every `writes` writes instead of after
+    /// each one; 0, the default, flushes every write. Up to `writes - 1`
+    /// writes can be lost on a crash, fewer when the write buffer fills and
+    /// flushes by itself first, or a read through this handle flushes it.
+    /// Ignored with `flush_interval`.
+    pub fn flush_every(mut self, writes: usize) -> Self {
+        self.flush_every = writes;
+        self
+    }
+
+    /// Make `set` read the current value first and skip the append when it
+    /// is unchanged. Saves log space for workloads that rewrite identical
+    /// values, at the cost of one extra read per set of an existing key.
+    pub fn skip_redundant_writes(mut self, skip: bool) -> Self {
+        self.skip_redundant_writes = skip;
+        self
+    }
+
+    /// Retry the log reads and writes of `get`, `set` and `remove` up to
+    /// `retries` times when they fail with `Interrupted` or `WouldBlock`.
+    /// Other errors are returned immediately.
+    pub fn io_retries(mut self, retries: usize) -> Self {
+        self.io_retries = retries;
+        self
+    }
+
+    /// Reserve `bytes` of log file up front with `File::set_len`, so the
+    /// file does not grow on every append. Records are written from the end
+    /// of the replayed log over the zero padding, and replay stops at the
+    /// first zero byte. Compaction shrinks the file back to `bytes`. Whether
+    /// the space is actually allocated, rather than left sparse, depends on
+    /// the filesystem.
+    pub fn preallocate_bytes(mut self, bytes: u64) -> Self {
+        self.preallocate_bytes = bytes;
+        self
+    }
+
+    /// Compact once the log reaches `bytes`, instead of the default 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Skip threshold-triggered compactions until `cooldown` has passed
+    /// since the last compaction, so a live set near the threshold does not
+    /// get rewritten on every write. `max_total_bytes` is still enforced
+    /// immediately.
+    pub fn compaction_cooldown(mut self, cooldown: Option<Duration>) -> Self {
+        self.compaction_cooldown = cooldown;
+        self
+    }
+
+    /// Check every value before it is written, by `set` as well as
+    /// `import_json` and `rename`. A value `validator` rejects fails the
+    /// write with `ValidationError` carrying its message, and nothing is
+    /// written.
+    pub fn value_validator(mut self, validator: impl Fn(&str) -> std::result::Result<(), String> + Send + Sync + 'static) -> Self {
+        self.value_validator = Some(ValueValidator(Arc::new(validator)));
+        self
+    }
+
+    /// Keep up to `versions` of the most recent values of each live key
+    /// when compacting, instead of only the current one, so `get_version`
+    /// can still reach them. 0 is treated as 1. Removed keys lose all their
+    /// versions, and `compact_from_scan` keeps only current values.
+    pub fn keep_versions(mut self, versions: usize) -> Self {
+        self.keep_versions = versions;
+        self
+    }
+
+    /// Serve `get` from a memory map of the log rather than a read per
+    /// call. The mapping is extended as the log grows and replaced after
+    /// compaction. Truncating the log from outside the store while it is
+    /// mapped is undefined behaviour.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Report every compaction, automatic or explicit, to `observer`.
+    pub fn compaction_observer(mut self, observer: impl CompactionObserver + 'static) -> Self {
+        self.compaction_observer = Some(ObserverHandle(Arc::new(observer)));
+        self
+    }
+
+    /// Make `close` compact the log before releasing it, so the store is
+    /// left holding only its live records. Dropping the store without
+    /// calling `close` does not compact.
+    pub fn compact_on_close(mut self, compact: bool) -> Self {
+        self.compact_on_close = compact;
+        self
+    }
+
+    /// Index only the keys `pred` accepts while replaying the log; other
+    /// keys read as missing. Such a store is always opened read-only, since
+    /// compacting it would drop the keys left out.
+    pub fn key_filter(mut self, pred: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.key_filter = Some(KeyFilter(Arc::new(pred)));
+        self
+    }
+
+    /// Back the index with the given kind of map instead of the default
+    /// ordered one. See `IndexKind` for what a hash index gives up.
+    pub fn index_kind(mut self, kind: IndexKind) -> Self {
+        self.index_kind = kind;
+        self
+    }
+
+    /// Choose how `bulk_load` treats a key that appears more than once in
+    /// its input. The default keeps the last value.
+    pub fn on_duplicate(mut self, on_duplicate: OnDuplicate) -> Self {
+        self.on_duplicate = on_duplicate;
+        self
+    }
+
+    /// Store values longer than `bytes` once encoded in files of their own
+    /// under `blobs` next to the log, leaving only the file name in the
+    /// log, so compaction copies the name rather than the value. Blob files
+    /// no live record refers to are deleted by the next compaction made
+    /// while no `iter` of the handle is open. Values streamed with
+    /// `begin_value` always go in the log.
+    pub fn blob_threshold(mut self, bytes: usize) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// Keep a second index from each live value to the keys holding it, for
+    /// `KvStore::keys_with_value`. Off by default, since it holds a copy of
+    /// every live value in memory and opening decodes every value in the
+    /// log, reading blob files too.
+    pub fn value_index(mut self, enabled: bool) -> Self {
+        self.value_index = enabled;
+        self
+    }
+
+    // opens the log for writing in place rather than appending, since a
+    // preallocated log ends in padding that records must overwrite
+    fn open_log_writer(&self, path: &path::Path) -> io::Result<File> {
+        self.log_open_options().write(true).create(true).truncate(false).open(path)
+    }
+
+    /// Create log files with permission bits `mode` (e.g. `0o600`), still
+    /// subject to the umask, instead of the default `0o666`. Existing files
+    /// keep their permissions until compaction replaces them.
+    #[cfg(unix)]
+    pub fn file_mode(mut self, mode: u32) -> Self {
+        self.file_mode = Some(mode);
+        self
+    }
+
+    // options for opening log files, carrying the configured mode for files
+    // they create
+    fn log_open_options(&self) -> fs::OpenOptions {
+        #[allow(unused_mut)]
+        let mut options = fs::OpenOptions::new();
+        #[cfg(unix)]
+        if let Some(mode) = self.file_mode {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode);
         }
-        
+        options
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        // self.print_index();
-        if let Some(_) = self.index.get(&key) {
+    fn file_name(&self, name: &str) -> String {
+        match &self.file_prefix {
+            Some(prefix) => format!("{prefix}-{name}"),
+            None => name.to_owned()
+        }
+    }
 
-            // serialize operation
-            let op = Op::Rm(key);
-            let mut serialized_op = serde_json::to_vec(& op)?;
-            serialized_op.extend_from_slice("\n".as_bytes());
+    pub fn open(self, path: impl Into<path::PathBuf>) -> Result<KvStore> {
+        self.open_with_progress(path, |_, _| ())
+    }
 
-            // seek to end
-            let mut file = fs::OpenOptions::new().append(true).create(true).open(&self.log_file)?;
+    /// Like `open`, calling `on_progress` with the bytes replayed so far and
+    /// the log file length after each record while the index is built. On
+    /// a preallocated log the last call falls short of the length by the
+    /// unused tail.
+    pub fn open_with_progress(mut self, path: impl Into<path::PathBuf>, mut on_progress: impl FnMut(u64, u64)) -> Result<KvStore> {
+        if self.key_filter.is_some() {
+            self.read_only = true;
+        }
+        let mut dirpath = path.into().clone();
+        if dirpath.exists() && !dirpath.is_dir() {
+            return Err(KvError::NotADirectoryError(dirpath));
+        }
+        let lock = if self.read_only {
+            None
+        } else {
+            Some(lock_file(&dirpath.join(self.file_name(LOCK_FILE_NAME)))?)
+        };
+        dirpath.push(self.file_name(LOG_FILE_NAME));
 
-            self.log_size = serialized_op.len() as u64;
-            // write to disk
-            file.write(& serialized_op.as_slice())?;
-            file.flush()?;
-            // update index
-            if let Op::Rm(k) = op {
-                self.index.remove(&k);
-            }
-            self.compact()?;
-            return Ok(())
+        // readers share a lock on the log itself, so they need not create
+        // anything; a writer only checks that no reader holds it
+        let (writer, reader_lock) = if self.read_only {
+            // the log must already exist
+            let log = File::open(&dirpath)?;
+            lock_outcome(log.try_lock_shared(), &dirpath)?;
+            (None, Some(log))
         } else {
-            return Err(KvError::KeyNotFoundError);
+            let file = self.open_log_writer(&dirpath)?;
+            lock_outcome(File::open(&dirpath)?.try_lock(), &dirpath)?;
+            (Some(Arc::new(Mutex::new(BufWriter::new(file)))), None)
+        };
+        let flusher = match (&writer, self.flush_interval) {
+            (Some(writer), Some(interval)) => Some(Flusher::spawn(writer.clone(), interval)),
+            _ => None
+        };
+
+        let log_id = match &reader_lock {
+            Some(log) => file_id(log)?,
+            None => None
+        };
+        let kv_store = KvStore{
+            index: Index::new(self.index_kind),
+            value_index: self.value_index.then(ValueIndex::default),
+            log_file: dirpath,
+            lock,
+            reader_lock,
+            log_id,
+            writer,
+            flusher,
+            log_size: 0,
+            log_base: 0,
+            last_compaction: None,
+            compaction_count: 0,
+            record_count: 0,
+            unflushed_writes: 0,
+            dead_bytes: 0,
+            log_opens: AtomicU64::new(0),
+            snapshots: Arc::new(()),
+            #[cfg(feature = "mmap")]
+            log_map: self.mmap.then(mmap::LogMap::default),
+            options: self,
+        };
+
+        kv_store.construct_index(&mut on_progress)
+    }
+
+    /// Opens the store in `target`, first moving the store staged in
+    /// `staging` into its place if `target` holds no log yet. The move is a
+    /// single directory rename, so `target` never shows a partial copy; it
+    /// needs `staging` on the same filesystem and `target` missing or an
+    /// empty directory. The staged log is replayed before the move, so a
+    /// corrupt one stays where it is. When `target` already holds a log,
+    /// or there is nothing staged, `target` is opened as is.
+    pub fn open_or_promote(self, staging: impl Into<path::PathBuf>, target: impl Into<path::PathBuf>) -> Result<KvStore> {
+        let staging = staging.into();
+        let target = target.into();
+        let log_name = self.file_name(LOG_FILE_NAME);
+        if !target.join(&log_name).exists() && staging.join(&log_name).exists() {
+            // the staged store is closed again before it is moved
+            drop(self.clone().open(&staging)?);
+            fs::rename(&staging, &target)?;
+            log::info!("promoted {} to {}", staging.display(), target.display());
         }
+        self.open(target)
     }
 
-    fn construct_index(mut self) -> Result<Self> {
+    /// Rewrites the log in `path` without the records that cannot be
+    /// parsed, keeping the latest valid value of every key. Each dropped
+    /// record is logged with its offset. The store must not be open.
+    pub fn repair(self, path: impl Into<path::PathBuf>) -> Result<RepairReport> {
+        let dirpath = path.into();
+        if !dirpath.is_dir() {
+            return Err(KvError::NotADirectoryError(dirpath));
+        }
+        let lock = lock_file(&dirpath.join(self.file_name(LOCK_FILE_NAME)))?;
+        let log_file = dirpath.join(self.file_name(LOG_FILE_NAME));
+
+        let mut live = BTreeMap::new();
+        let mut dropped = 0;
         let mut offset = 0;
-        let mut file_handle = File::open(&self.log_file)?;
-        let buf_reader = io::BufReader::new( &mut file_handle);
-        for line in buf_reader.lines() {
-            let content = line.unwrap();
-            // parse line
-            match serde_json::from_slice::<Op>(content.as_bytes())? {
-                Op::Set(k, _) => {
-                    self.index.insert(k, offset);
+        let mut reader = BufReader::new(File::open(&log_file)?);
+        loop {
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line)? == 0 || line[0] == 0 {
+                break;
+            }
+            let len = line.len() as u64;
+            match serde_json::from_slice::<Op>(&line) {
+                Ok(Op::Set(k, _)) | Ok(Op::SetWithMeta { key: k, .. }) => {
+                    // a valid record torn off before its newline
+                    if line.last() != Some(&b'\n') {
+                        line.push(b'\n');
+                    }
+                    live.insert(k, line);
                 },
-                Op::Rm(k) => {
-                    self.index.remove(&k);
+                Ok(Op::Rm(k)) => {
+                    live.remove(&k);
                 },
-                _ => ()
+                Ok(Op::Get(_)) => (),
+                Err(e) => {
+                    log::warn!("dropping unparseable record at offset {offset} of {}: {e}", log_file.display());
+                    dropped += 1;
+                }
             }
+            offset += len;
+        }
 
-            offset += content.len() as u64 + 1;
+        let mut tmp_name = log_file.clone().into_os_string();
+        tmp_name.push(".repair");
+        let tmp_file = path::PathBuf::from(tmp_name);
+        if tmp_file.exists() {
+            fs::remove_file(&tmp_file)?;
+        }
+        {
+            let mut file = BufWriter::new(self.log_open_options().write(true).create_new(true).open(&tmp_file)?);
+            for line in live.values() {
+                file.write_all(line)?;
+            }
+            file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
         }
+        // the rewritten log moves records, so positions in the old one are gone
+        let base_file = dirpath.join(self.file_name(BASE_FILE_NAME));
+        write_log_base(&base_file, read_log_base(&base_file)? + fs::metadata(&log_file)?.len())?;
+        fs::rename(&tmp_file, &log_file)?;
+        lock.unlock()?;
 
-        Ok(self)
+        Ok(RepairReport { dropped, live_keys: live.len() })
+    }
+}
+
+
+const LOG_FILE_NAME: &str = "store";
+const LOCK_FILE_NAME: &str = "store.lock";
+const BLOB_DIR_NAME: &str = "blobs";
+// holds the change position of the first byte of the current log
+const BASE_FILE_NAME: &str = "store.base";
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// amortized BTreeMap node bookkeeping (parent pointer, lengths, edges) per entry
+const BTREE_ENTRY_OVERHEAD: usize = 16;
+
+// reads the raw record starting at `offset`, including its trailing newline;
+// returns None at the end of the log
+fn read_record(reader: &mut impl BufRead, offset: u64) -> Result<Option<String>> {
+    let mut buf = Vec::new();
+    // a zero byte is preallocated padding past the last record
+    if reader.read_until(b'\n', &mut buf)? == 0 || buf[0] == 0 {
+        return Ok(None);
+    }
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|_| KvError::NonUtf8RecordError { offset })
+}
+
+// reads and decodes the value of the `Set` record for `key` at `offset`
+fn read_value_at(reader: &mut BufReader<File>, blobs: &path::Path, key: &str, offset: u64) -> Result<String> {
+    let (value, meta) = read_set_at(reader, key, offset)?;
+    decode_value(blobs, value, &meta)
+}
+
+// decodes a value as stored in a record, reading it from `blobs` first if
+// the record only names its blob file
+fn decode_value(blobs: &path::Path, value: String, meta: &RecordMeta) -> Result<String> {
+    if meta.blob {
+        meta.compression.decode(fs::read_to_string(blobs.join(value))?)
+    } else {
+        meta.compression.decode(value)
+    }
+}
+
+// reads the still-encoded value and the metadata of the `Set` record for
+// `key` at `offset`; plain `Set` records have default metadata
+fn read_set_at(reader: &mut BufReader<File>, key: &str, offset: u64) -> Result<(String, RecordMeta)> {
+    reader.seek(io::SeekFrom::Start(offset))?;
+    let line = read_record(reader, offset)?.ok_or(KvError::DanglingIndexError(offset))?;
+    parse_set(line.as_bytes(), key, offset)
+}
+
+// parses `line`, read from `offset`, as the `Set` record for `key`
+fn parse_set(line: &[u8], key: &str, offset: u64) -> Result<(String, RecordMeta)> {
+    let op = serde_json::from_slice::<Op>(line)?;
+
+    let (k, v, meta) = match op {
+        Op::Set(k, v) => (k, v, RecordMeta::default()),
+        Op::SetWithMeta { key, value, meta } => (key, value, meta),
+        _ => return Err(KvError::InvalidCommandError)
+    };
+
+    // the index always points at the latest record for its key
+    debug_assert_eq!(k, key, "index entry points at another key's record");
+    if k == key {
+        Ok((v, meta))
+    } else {
+        Err(KvError::DanglingIndexError(offset))
+    }
+}
+
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
     }
+}
+
+fn is_retryable(e: &KvError) -> bool {
+    matches!(e, KvError::IoError(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock))
+}
 
-    fn compact(&mut self) -> Result<()> {
+// runs `op`, running it again up to `retries` more times while it fails
+// with a transient I/O error (`Interrupted` or `WouldBlock`); `op` must be
+// safe to repeat after a partial failure
+fn retry_io<T>(retries: usize, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_retryable(&e) => attempt += 1,
+            result => return result
+        }
+    }
+}
 
-        if self.log_size >= 1024*1024 {
-            let mut content = String::new();
-            {
-                let file_handle = File::open(&self.log_file)?;
-                let mut buf = BufReader::new(file_handle);
-                for i in self.index.iter() {
-                    buf.seek(io::SeekFrom::Start(*i.1))?;
-                    buf.read_line(&mut content)?;
+// like `write_all`, but gives up after `retries` transient errors instead of
+// retrying `Interrupted` forever; bytes already written are not rewritten
+fn write_all_retrying(writer: &mut impl Write, mut buf: &[u8], retries: usize) -> Result<()> {
+    let mut attempt = 0;
+    while !buf.is_empty() {
+        match writer.write(buf) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+            Ok(n) => buf = &buf[n..],
+            Err(e) => {
+                let e = KvError::from(e);
+                if attempt < retries && is_retryable(&e) {
+                    attempt += 1;
+                } else {
+                    return Err(e);
                 }
             }
-            
-            let mut file = File::create(&self.log_file)?;
-            file.write(content.as_bytes())?;
-            file.flush()?;
-            self.log_size = content.len() as u64;
-            Ok(())
-        } else {
+        }
+    }
+    Ok(())
+}
+
+// checks that a record of `len` bytes fits an index entry
+fn record_len(len: u64) -> Result<u32> {
+    u32::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record larger than 4 GiB").into())
+}
+
+// the current time as stored in `RecordMeta::timestamp`
+fn now_millis() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .ok()
+}
+
+fn into_io_error(e: KvError) -> io::Error {
+    match e {
+        KvError::IoError(e) => e,
+        e => io::Error::other(e.to_string())
+    }
+}
+
+// takes the exclusive advisory lock guarding a store
+fn lock_file(path: &path::Path) -> Result<File> {
+    let lock = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    lock_outcome(lock.try_lock(), path)?;
+    Ok(lock)
+}
+
+// the change position of the start of the log, from the base file at
+// `path`; 0 until the first compaction writes it
+fn read_log_base(path: &path::Path) -> Result<u64> {
+    match fs::read_to_string(path) {
+        Ok(base) => base.trim().parse().map_err(|_| KvError::IoError(io::Error::new(io::ErrorKind::InvalidData, "malformed log base file"))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into())
+    }
+}
+
+// replaced by a rename, so a crash leaves either the old or the new base
+fn write_log_base(path: &path::Path, base: u64) -> Result<()> {
+    let mut tmp_name = path.to_owned().into_os_string();
+    tmp_name.push(".tmp");
+    fs::write(&tmp_name, base.to_string())?;
+    fs::rename(&tmp_name, path)?;
+    Ok(())
+}
+
+// maps a non-blocking lock attempt on `path` to `LockedError` if it is held
+fn lock_outcome(result: std::result::Result<(), fs::TryLockError>, path: &path::Path) -> Result<()> {
+    match result {
+        Ok(()) => {
+            log::debug!("acquired lock on {}", path.display());
             Ok(())
+        },
+        Err(fs::TryLockError::WouldBlock) => Err(KvError::LockedError),
+        Err(fs::TryLockError::Error(e)) => Err(KvError::IoError(e)),
+    }
+}
+
+// identifies the file behind `file` across renames; None where the platform
+// gives no such id
+fn file_id(file: &File) -> io::Result<Option<(u64, u64)>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = file.metadata()?;
+        Ok(Some((metadata.dev(), metadata.ino())))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        Ok(None)
+    }
+}
+
+/// A value located in the log but not read yet, returned by
+/// `KvStore::get_lazy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueHandle {
+    key: String,
+    offset: u64,
+}
+
+impl ValueHandle {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Reads the value from `store`, the store the handle came from. If the
+    /// key has been written or compacted since, its current value is read
+    /// instead; if it has been removed, this fails with `KeyNotFoundError`.
+    pub fn read(&self, store: &KvStore) -> Result<String> {
+        let offset = match store.index.get(&self.key) {
+            Some((offset, _)) => *offset,
+            None => return Err(KvError::KeyNotFoundError)
+        };
+        if offset != self.offset {
+            log::debug!("value handle for {:?} is stale, reading offset {offset}", self.key);
+        }
+        let mut buf_reader = io::BufReader::new(store.open_log()?);
+        read_value_at(&mut buf_reader, &store.blob_dir(), &self.key, offset)
+    }
+}
+
+/// Iterator over a snapshot of the live pairs, returned by `KvStore::iter`.
+pub struct Iter {
+    entries: std::vec::IntoIter<(String, u64)>,
+    reader: BufReader<File>,
+    blobs: path::PathBuf,
+    // keeps the store from deleting blob files the snapshot may still read
+    _snapshot: Arc<()>,
+}
+
+impl Iterator for Iter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, offset) = self.entries.next()?;
+        Some(read_value_at(&mut self.reader, &self.blobs, &key, offset).map(|value| (key, value)))
+    }
+}
+
+/// Iterator over every record of the log in physical order, with its
+/// offset, returned by `KvStore::raw_records`.
+pub struct RawRecords {
+    reader: BufReader<File>,
+    offset: u64,
+    end: u64,
+}
+
+impl Iterator for RawRecords {
+    type Item = Result<(u64, Op)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.end {
+            return None;
+        }
+        let offset = self.offset;
+        let line = match read_record(&mut self.reader, offset) {
+            Ok(Some(line)) => line,
+            Ok(None) => return None,
+            Err(e) => {
+                // offsets past a bad record cannot be trusted
+                self.end = offset;
+                return Some(Err(e));
+            }
+        };
+        self.offset += line.len() as u64;
+        Some(serde_json::from_slice(line.as_bytes()).map(|op| (offset, op)).map_err(KvError::from))
+    }
+}
+
+/// Streams a single value into the log, returned by `KvStore::begin_value`.
+/// Write the value's UTF-8 bytes in chunks of any size, then call `commit`;
+/// dropping the writer without committing truncates the partial record.
+pub struct ValueWriter<'a> {
+    store: &'a mut KvStore,
+    key: String,
+    start: u64,
+    written: u64,
+    // the start of a UTF-8 sequence split across writes
+    pending: Vec<u8>,
+    committed: bool,
+}
+
+impl ValueWriter<'_> {
+    fn emit(&mut self, bytes: &[u8]) -> Result<()> {
+        let writer = self.store.writer.as_ref().ok_or(KvError::ReadOnlyError)?;
+        write_all_retrying(&mut *lock_writer(writer), bytes, self.store.options.io_retries)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Ends the record and makes the value visible under its key. Fails
+    /// with `CorruptValueError`, writing nothing more, if the value ends
+    /// partway through a UTF-8 sequence, and with `RecordTooLargeError`,
+    /// dropping the record, if it is larger than `max_total_bytes`.
+    pub fn commit(mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            return Err(KvError::CorruptValueError);
+        }
+        self.emit(b"\"}}\n")?;
+        self.store.check_fits(self.written)?;
+        if self.store.flush_due() {
+            self.store.flush_writer()?;
+        }
+        let len = record_len(self.written)?;
+        self.committed = true;
+        self.store.log_size += self.written;
+        self.store.record_count += 1;
+        let key = std::mem::take(&mut self.key);
+        self.store.index_set(key.clone(), self.start, len);
+        if self.store.value_index.is_some() {
+            // the value was streamed rather than held, so read it back
+            let value = self.store.get(key.clone())?.ok_or(KvError::KeyNotFoundError)?;
+            if let Some(values) = &mut self.store.value_index {
+                values.insert(key, value);
+            }
+        }
+        self.store.maybe_compact()
+    }
+}
+
+impl Write for ValueWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            // an incomplete sequence at the end may be finished by the next write
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => {
+                self.pending.truncate(self.pending.len() - buf.len());
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "value is not valid UTF-8"));
+            }
+        };
+        let text = std::str::from_utf8(&self.pending[..valid]).expect("validated above");
+        let escaped = serde_json::to_vec(text)?;
+        // strip the quotes, the record header and footer supply them
+        self.emit(&escaped[1..escaped.len() - 1]).map_err(into_io_error)?;
+        self.pending.drain(..valid);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ValueWriter<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            if let Err(e) = self.store.truncate_log(self.start) {
+                log::warn!("failed to remove partial record from {}: {e}", self.store.log_file.display());
+            }
+        }
+    }
+}
+
+type LogWriter = Arc<Mutex<BufWriter<File>>>;
+
+// background thread flushing the log writer on an interval
+struct Flusher {
+    stop: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl Flusher {
+    fn spawn(writer: LogWriter, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || loop {
+            // a message or a disconnected sender both mean stop
+            let done = !matches!(stopped.recv_timeout(interval), Err(mpsc::RecvTimeoutError::Timeout));
+            if let Err(e) = lock_writer(&writer).flush() {
+                log::warn!("background flush failed: {e}");
+            }
+            if done {
+                break;
+            }
+        });
+        Flusher { stop, handle }
+    }
+
+    // flushes one last time and waits for the thread to exit
+    fn stop(self) {
+        drop(self.stop);
+        if self.handle.join().is_err() {
+            log::warn!("background flush thread panicked");
         }
     }
 }
+
+fn lock_writer(writer: &LogWriter) -> MutexGuard<'_, BufWriter<File>> {
+    writer.lock().expect("log writer lock poisoned")
+}
+
+pub struct KvStore {
+    // offset and length of the latest record of every live key
+    index: Index,
+    // set by `KvStoreBuilder::value_index`
+    value_index: Option<ValueIndex>,
+    log_file: path::PathBuf,
+    // held for the lifetime of the handle; dropping it releases the lock
+    lock: Option<File>,
+    // shared lock on the log held by read-only handles
+    reader_lock: Option<File>,
+    // device and inode of the locked log, to notice it being replaced
+    log_id: Option<(u64, u64)>,
+    // None for read-only handles
+    writer: Option<LogWriter>,
+    flusher: Option<Flusher>,
+    log_size: u64,
+    // change position of the start of the log: the size of every log it
+    // replaced, kept in the base file so it survives reopening
+    log_base: u64,
+    last_compaction: Option<Instant>,
+    compaction_count: u64,
+    // records in the log, live or not
+    record_count: usize,
+    // writes since the last flush under `flush_every`
+    unflushed_writes: usize,
+    // log bytes no longer live, since the last compaction
+    dead_bytes: u64,
+    log_opens: AtomicU64,
+    // one clone per live `Iter`
+    snapshots: Arc<()>,
+    #[cfg(feature = "mmap")]
+    log_map: Option<mmap::LogMap>,
+    options: KvStoreBuilder,
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<path::PathBuf>) -> Result<KvStore> {
+        KvStoreBuilder::new().open(path)
+    }
+
+    /// Opens the store in `path`, reporting replay progress to
+    /// `on_progress`. See `KvStoreBuilder::open_with_progress`.
+    pub fn open_with_progress(path: impl Into<path::PathBuf>, on_progress: impl FnMut(u64, u64)) -> Result<KvStore> {
+        KvStoreBuilder::new().open_with_progress(path, on_progress)
+    }
+
+    /// Opens an existing store for reads of the keys `pred` accepts only.
+    /// See `KvStoreBuilder::key_filter`.
+    pub fn open_with_key_filter(path: impl Into<path::PathBuf>, pred: impl Fn(&str) -> bool + Send + Sync + 'static) -> Result<KvStore> {
+        KvStoreBuilder::new().key_filter(pred).open(path)
+    }
+
+    /// Opens `target`, promoting the store in `staging` to it first if
+    /// `target` has none. See `KvStoreBuilder::open_or_promote`.
+    pub fn open_or_promote(staging: impl Into<path::PathBuf>, target: impl Into<path::PathBuf>) -> Result<KvStore> {
+        KvStoreBuilder::new().open_or_promote(staging, target)
+    }
+
+    /// Opens an existing store for reads only. See `KvStoreBuilder::read_only`.
+    pub fn open_read_only(path: impl Into<path::PathBuf>) -> Result<KvStore> {
+        KvStoreBuilder::new().read_only(true).open(path)
+    }
+
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
+    /// Repairs the store in `path`. See `KvStoreBuilder::repair`.
+    pub fn repair(path: impl Into<path::PathBuf>) -> Result<RepairReport> {
+        KvStoreBuilder::new().repair(path)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.check_writable()?;
+        if self.options.skip_redundant_writes
+            && self.index.contains_key(&key)
+            && self.get(key.clone())?.as_ref() == Some(&value)
+        {
+            return Ok(());
+        }
+        let op = self.set_op(key, value)?;
+        self.append(vec![op])?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    /// Sets `key` only if it is not already present, returning whether the
+    /// value was written. Nothing is appended when the key exists.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.check_writable()?;
+        if self.index.contains_key(&key) {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Rewrites the latest record of `key` with the current time, so
+    /// `last_modified` advances while the value stays as it is, stored as
+    /// it was written. Returns false, writing nothing, if the key is
+    /// missing.
+    pub fn touch(&mut self, key: &str) -> Result<bool> {
+        self.check_writable()?;
+        let Some((offset, _)) = self.index.get(key) else {
+            return Ok(false);
+        };
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        let (value, meta) = read_set_at(&mut buf_reader, key, *offset)?;
+        let op = Op::SetWithMeta {
+            key: key.to_owned(),
+            value,
+            meta: RecordMeta { timestamp: now_millis(), ..meta },
+        };
+        self.append(vec![op])?;
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    /// Returns the value of `key`. Each call reads through its own log
+    /// handle, so `get` only needs `&self`: a store behind an `Arc` can be
+    /// read from several threads at once, which only contend briefly on
+    /// the writer lock to flush buffered writes. Writing still needs
+    /// `&mut self`; use `SharedKvStore` to mix the two across threads.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        #[cfg(feature = "mmap")]
+        if let (Some(log_map), Some((offset, _))) = (&self.log_map, self.index.get(&key)) {
+            let (value, meta) = log_map.read_set_at(|| self.open_log(), &key, *offset)?;
+            return decode_value(&self.blob_dir(), value, &meta).map(Some);
+        }
+        if let Some((offset, _)) = self.index.get(&key) {
+            let retries = self.options.io_retries;
+            let mut buf_reader = io::BufReader::new(retry_io(retries, || self.open_log())?);
+            // read_value_at seeks first, so a failed read can simply be redone
+            let blobs = self.blob_dir();
+            retry_io(retries, || read_value_at(&mut buf_reader, &blobs, &key, *offset)).map(Some)
+        } else {
+            Ok(None)
+        }
+        
+    }
+
+    /// Locates the value of `key` without reading it; `ValueHandle::read`
+    /// reads it when needed. Returns `None` if the key is missing.
+    pub fn get_lazy(&self, key: &str) -> Option<ValueHandle> {
+        self.index.get(key).map(|(offset, _)| ValueHandle { key: key.to_owned(), offset: *offset })
+    }
+
+    /// Like `get`, but also returns the log offset the value was read from,
+    /// which `read_op_at` can later re-read directly.
+    pub fn get_with_offset(&self, key: &str) -> Result<Option<(String, u64)>> {
+        match self.index.get(key) {
+            Some((offset, _)) => {
+                let mut buf_reader = io::BufReader::new(self.open_log()?);
+                Ok(Some((read_value_at(&mut buf_reader, &self.blob_dir(), key, *offset)?, *offset)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Reads the record starting at `offset` as written, so values stay
+    /// compressed if they were written compressed. Fails with
+    /// `OffsetGoneError` at or past the end of the log.
+    pub fn read_op_at(&self, offset: u64) -> Result<Op> {
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        buf_reader.seek(io::SeekFrom::Start(offset))?;
+        let line = read_record(&mut buf_reader, offset)?.ok_or(KvError::OffsetGoneError(offset))?;
+        Ok(serde_json::from_slice(line.as_bytes())?)
+    }
+
+    /// Returns the live pairs with keys between `start` and `end`, in key
+    /// order. An empty or inverted range returns no pairs.
+    pub fn get_range(&self, start: Bound<&str>, end: Bound<&str>) -> Result<Vec<(String, String)>> {
+        let entries = self.index.range(start, end)?;
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        let blobs = self.blob_dir();
+        entries
+            .map(|(key, (offset, _))| Ok((key.clone(), read_value_at(&mut buf_reader, &blobs, key, *offset)?)))
+            .collect()
+    }
+
+    /// Returns up to `limit` live keys after `after` (or from the first key
+    /// when `None`), in key order, and the token to pass as `after` for the
+    /// next page: the last key returned, or `None` once no keys are left.
+    /// Keys written between calls are picked up if they sort after the
+    /// token.
+    ///
+    /// Fails with an `InvalidInput` `IoError` if `limit` is 0, since an
+    /// empty first page has no key to carry on from.
+    pub fn list_page(&self, after: Option<&str>, limit: usize) -> Result<(Vec<String>, Option<String>)> {
+        if limit == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "list_page needs a limit of at least 1").into());
+        }
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut keys = self.index.range(start, Bound::Unbounded)?.map(|(key, _)| key);
+
+        let page: Vec<String> = keys.by_ref().take(limit).cloned().collect();
+        let next = if keys.next().is_some() {
+            page.last().cloned()
+        } else {
+            None
+        };
+        Ok((page, next))
+    }
+
+    /// Returns when `key` was last set, read from its latest record. `None`
+    /// if the key is missing or its record predates write timestamps.
+    pub fn last_modified(&self, key: &str) -> Result<Option<SystemTime>> {
+        let Some((offset, _)) = self.index.get(key) else {
+            return Ok(None);
+        };
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        let (_, meta) = read_set_at(&mut buf_reader, key, *offset)?;
+        Ok(meta.timestamp.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)))
+    }
+
+    /// Like `get`, but tells a removed key apart from one that was never
+    /// set by scanning the log for its tombstone. Compaction drops
+    /// tombstones, so after it a removed key reports `NeverExisted`.
+    pub fn status(&self, key: &str) -> Result<KeyStatus> {
+        if let Some(value) = self.get(key.to_owned())? {
+            return Ok(KeyStatus::Present(value));
+        }
+
+        let mut offset = 0;
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        while let Some(line) = read_record(&mut buf_reader, offset)? {
+            offset += line.len() as u64;
+            if matches!(serde_json::from_slice::<Op>(line.as_bytes())?, Op::Rm(k) if k == key) {
+                return Ok(KeyStatus::Deleted);
+            }
+        }
+        Ok(KeyStatus::NeverExisted)
+    }
+
+    /// Looks up several keys through a single log handle. A plain `KvStore`
+    /// cannot be written while borrowed, so this is simply a batched `get`;
+    /// `SharedKvStore::snapshot_get` holds its read lock across the batch so
+    /// the values come from a single point in time.
+    pub fn snapshot_get(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        let blobs = self.blob_dir();
+        keys.iter()
+            .map(|key| match self.index.get(key) {
+                Some((offset, _)) => read_value_at(&mut buf_reader, &blobs, key, *offset).map(Some),
+                None => Ok(None)
+            })
+            .collect()
+    }
+
+    /// Returns the value of the `versions_back`-th most recent `Set` of
+    /// `key` still in the log, where 0 is the latest. Superseded values are
+    /// only kept until the next compaction; `None` means fewer versions
+    /// exist. This scans the whole log and leaves the index untouched.
+    pub fn get_version(&self, key: &str, versions_back: usize) -> Result<Option<String>> {
+        let mut versions: Vec<_> = self.records_of(key)?.into_iter().flatten().collect();
+
+        // avoids `versions_back + 1`, which overflows for usize::MAX
+        match versions.len().checked_sub(1).and_then(|last| last.checked_sub(versions_back)) {
+            Some(i) => {
+                let (value, meta) = versions.swap_remove(i);
+                decode_value(&self.blob_dir(), value, &meta).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Returns up to `max` values of `key`, most recent first, going back
+    /// no further than its last removal. Like `get_version`, this scans the
+    /// whole log and only sees values not yet compacted away.
+    pub fn get_history(&self, key: &str, max: usize) -> Result<Vec<String>> {
+        let records = self.records_of(key)?;
+        records.into_iter()
+            .rev()
+            .map_while(|record| record)
+            .take(max)
+            .map(|(value, meta)| decode_value(&self.blob_dir(), value, &meta))
+            .collect()
+    }
+
+    /// Counts the sets of `key` still in the log other than the live one:
+    /// the dead records the next compaction would drop for it. After a
+    /// removal, every set before it counts. This scans the whole log.
+    pub fn stale_versions(&self, key: &str) -> Result<usize> {
+        let sets = self.records_of(key)?.into_iter().flatten().count();
+        Ok(if self.index.contains_key(key) { sets.saturating_sub(1) } else { sets })
+    }
+
+    // returns the records for `key` still in the log, in log order: the
+    // still-encoded value and metadata of each set, or None for each removal
+    fn records_of(&self, key: &str) -> Result<Vec<Option<(String, RecordMeta)>>> {
+        let mut records = Vec::new();
+        let mut offset = 0;
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        while let Some(line) = read_record(&mut buf_reader, offset)? {
+            offset += line.len() as u64;
+            match serde_json::from_slice::<Op>(line.as_bytes())? {
+                Op::Set(k, v) if k == key => records.push(Some((v, RecordMeta::default()))),
+                Op::SetWithMeta { key: k, value, meta } if k == key => records.push(Some((value, meta))),
+                Op::Rm(k) if k == key => records.push(None),
+                _ => ()
+            }
+        }
+        Ok(records)
+    }
+
+    // calls `f` with every live key and value, in key order, reading the log
+    // through a single handle
+    fn scan_live(&self, mut f: impl FnMut(&String, String) -> Result<()>) -> Result<()> {
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        let blobs = self.blob_dir();
+        for (key, (offset, _)) in self.index.iter() {
+            let value = read_value_at(&mut buf_reader, &blobs, key, *offset)?;
+            f(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the live keys whose value satisfies `pred`, in key order.
+    /// Every live value is read from disk to test it, so this takes time
+    /// linear in the size of the live set.
+    pub fn find(&self, pred: impl Fn(&str) -> bool) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        self.scan_live(|key, value| {
+            if pred(&value) {
+                keys.push(key.clone());
+            }
+            Ok(())
+        })?;
+        Ok(keys)
+    }
+
+    /// Removes every key, returning the live pairs in key order. The log is
+    /// replaced by an empty one the same way compaction replaces it, so
+    /// iterators made before the call still see the old pairs.
+    pub fn drain(&mut self) -> Result<Vec<(String, String)>> {
+        self.check_writable()?;
+        let pairs = self.iter()?.collect::<Result<_>>()?;
+        self.swap_log("")?;
+        self.remove_unused_blobs("")?;
+        log::info!("drained {} keys from {}", self.index.len(), self.log_file.display());
+        self.index.clear();
+        if let Some(values) = &mut self.value_index {
+            values.clear();
+        }
+        self.log_size = 0;
+        self.record_count = 0;
+        self.dead_bytes = 0;
+        self.invalidate_map();
+        self.position_writer()?;
+        Ok(pairs)
+    }
+
+    /// Returns an iterator over the live pairs, in key order, as of the time
+    /// of the call. The iterator owns a snapshot of the index and a handle to
+    /// the current log file: later writes are not visible to it, and because
+    /// compaction replaces the log file rather than rewriting it in place,
+    /// compacting while iterating does not disturb it.
+    pub fn iter(&self) -> Result<Iter> {
+        let entries: Vec<(String, u64)> = self.index.iter().map(|(k, (offset, _))| (k.clone(), *offset)).collect();
+        Ok(Iter {
+            entries: entries.into_iter(),
+            reader: io::BufReader::new(self.open_log()?),
+            blobs: self.blob_dir(),
+            _snapshot: self.snapshots.clone(),
+        })
+    }
+
+    /// Returns a SHA-256 digest of the live pairs in key order. Stores with
+    /// the same live data agree on the digest whatever their log history.
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        self.scan_live(|key, value| {
+            // length prefixes keep ("ab", "c") distinct from ("a", "bc")
+            for field in [key.as_bytes(), value.as_bytes()] {
+                hasher.update((field.len() as u64).to_le_bytes());
+                hasher.update(field);
+            }
+            Ok(())
+        })?;
+        Ok(hasher.finalize().into())
+    }
+
+    /// Returns an iterator over every record in the log, superseded sets
+    /// and tombstones included, in the order they were written. Unlike
+    /// `iter`, values are returned as stored, compressed or not. Records
+    /// written after the call are not visited.
+    pub fn raw_records(&self) -> Result<RawRecords> {
+        Ok(RawRecords {
+            reader: io::BufReader::new(self.open_log()?),
+            offset: 0,
+            end: self.log_size,
+        })
+    }
+
+    /// Returns true if both stores hold the same live pairs, however they
+    /// were written. Compares key counts first, then `digest`s.
+    pub fn content_equals(&self, other: &KvStore) -> Result<bool> {
+        Ok(self.len() == other.len() && self.digest()? == other.digest()?)
+    }
+
+    /// Writes all live pairs as CSV with a `key,value` header, in key order.
+    /// Fields containing commas, quotes or line breaks are quoted.
+    pub fn export_csv(&self, mut writer: impl Write) -> Result<()> {
+        writeln!(writer, "key,value")?;
+        self.scan_live(|key, value| {
+            writeln!(writer, "{},{}", csv_field(key), csv_field(&value))?;
+            Ok(())
+        })?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes all live pairs as a single JSON object mapping keys to values,
+    /// in key order.
+    pub fn export_json(&self, writer: impl Write) -> Result<()> {
+        self.export_filtered(writer, |_| true)?;
+        Ok(())
+    }
+
+    /// Like `export_json`, but only writes the pairs whose key satisfies
+    /// `pred`, returning how many were written. Values of other keys are
+    /// not read.
+    pub fn export_filtered(&self, mut writer: impl Write, pred: impl Fn(&str) -> bool) -> Result<usize> {
+        let mut exported = 0;
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        writer.write_all(b"{")?;
+        for (key, (offset, _)) in self.index.iter().filter(|(key, _)| pred(key)) {
+            if exported > 0 {
+                writer.write_all(b",")?;
+            }
+            exported += 1;
+            serde_json::to_writer(&mut writer, key)?;
+            writer.write_all(b":")?;
+            serde_json::to_writer(&mut writer, &read_value_at(&mut buf_reader, &self.blob_dir(), key, *offset)?)?;
+        }
+        writer.write_all(b"}")?;
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    /// Sets every pair of a JSON object as written by `export_json`, in one
+    /// append, and returns how many were set. Existing keys not in the
+    /// object are kept. Nothing is written if the input fails to parse.
+    pub fn import_json(&mut self, reader: impl Read) -> Result<usize> {
+        self.check_writable()?;
+        let pairs: BTreeMap<String, String> = serde_json::from_reader(reader)?;
+        let ops = pairs.into_iter()
+            .map(|(key, value)| self.set_op(key, value))
+            .collect::<Result<Vec<Op>>>()?;
+
+        let imported = ops.len();
+        if imported > 0 {
+            self.append(ops)?;
+            self.maybe_compact()?;
+        }
+        Ok(imported)
+    }
+
+    /// Sets all `pairs` in one append and returns how many keys were set.
+    /// A key given more than once is handled as `KvStoreBuilder::on_duplicate`
+    /// says: by default its last value is the one written. Nothing is
+    /// written if any pair is rejected.
+    pub fn bulk_load(&mut self, pairs: impl IntoIterator<Item = (String, String)>) -> Result<usize> {
+        self.check_writable()?;
+        let mut latest = BTreeMap::new();
+        for (key, value) in pairs {
+            match latest.entry(key) {
+                Entry::Occupied(entry) if self.options.on_duplicate == OnDuplicate::Error => {
+                    return Err(KvError::DuplicateKeyError(entry.key().clone()));
+                },
+                Entry::Occupied(mut entry) => {
+                    entry.insert(value);
+                },
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                }
+            }
+        }
+        let ops = latest.into_iter()
+            .map(|(key, value)| self.set_op(key, value))
+            .collect::<Result<Vec<Op>>>()?;
+
+        let loaded = ops.len();
+        if loaded > 0 {
+            self.append(ops)?;
+            self.maybe_compact()?;
+        }
+        Ok(loaded)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.check_writable()?;
+        if self.index.contains_key(&key) {
+            self.append(vec![Op::Rm(key)])?;
+            self.maybe_compact()?;
+            Ok(())
+        } else if self.options.strict_remove {
+            Err(KvError::KeyNotFoundError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Removes all present `keys` with a single append and flush. Returns,
+    /// per key, whether it existed; missing keys are reported as false
+    /// rather than failing, regardless of `strict_remove`.
+    pub fn remove_batch(&mut self, keys: &[String]) -> Result<Vec<bool>> {
+        self.check_writable()?;
+        let mut removed = BTreeSet::new();
+        let mut existed = Vec::with_capacity(keys.len());
+        let mut ops = Vec::new();
+        for key in keys {
+            let present = self.index.contains_key(key) && removed.insert(key);
+            if present {
+                ops.push(Op::Rm(key.clone()));
+            }
+            existed.push(present);
+        }
+
+        if !ops.is_empty() {
+            self.append(ops)?;
+            self.maybe_compact()?;
+        }
+        Ok(existed)
+    }
+
+    /// Removes every key starting with `prefix` in one append, returning how
+    /// many were removed.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Result<usize> {
+        self.check_writable()?;
+        // keys sharing the prefix are contiguous from `prefix` onwards
+        let ops: Vec<Op> = self.index
+            .range(Bound::Included(prefix), Bound::Unbounded)?
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| Op::Rm(k.clone()))
+            .collect();
+
+        let removed = ops.len();
+        if removed > 0 {
+            self.append(ops)?;
+            self.maybe_compact()?;
+        }
+        Ok(removed)
+    }
+
+    /// Appends records read from another store's `changes_since`, in order,
+    /// on the follower side of replication. Their offsets in the source log
+    /// are ignored; each record lands at the end of this log. Fails with
+    /// `InvalidCommandError` before writing anything if a record is a `Get`
+    /// or only names a blob file, which `changes_since` never returns.
+    pub fn apply_raw(&mut self, records: &[(u64, Op)]) -> Result<()> {
+        self.check_writable()?;
+        if records.iter().any(|(_, op)| matches!(op, Op::Get(_) | Op::SetWithMeta { meta: RecordMeta { blob: true, .. }, .. })) {
+            return Err(KvError::InvalidCommandError);
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        self.append(records.iter().map(|(_, op)| op.clone()).collect())?;
+        self.maybe_compact()
+    }
+
+    /// Starts streaming a value for `key` straight into the log, so a large
+    /// value need not be held in memory. The value is stored uncompressed
+    /// whatever the `compression` option, and the store cannot be used
+    /// until the returned writer is committed or dropped. Fails with
+    /// `ValidationError` when a `value_validator` is set, since a streamed
+    /// value cannot be checked before it is written.
+    pub fn begin_value(&mut self, key: String) -> Result<ValueWriter<'_>> {
+        self.check_writable()?;
+        if self.options.value_validator.is_some() {
+            return Err(KvError::ValidationError("streamed values cannot be validated".to_owned()));
+        }
+
+        // the fields of a SetWithMeta record, with the value left open
+        let meta = RecordMeta { compression: Compression::None, timestamp: now_millis(), blob: false };
+        let mut header = br#"{"SetWithMeta":{"key":"#.to_vec();
+        serde_json::to_writer(&mut header, &key)?;
+        header.extend_from_slice(br#","meta":"#);
+        serde_json::to_writer(&mut header, &meta)?;
+        header.extend_from_slice(br#","value":""#);
+
+        let start = self.log_size;
+        let mut writer = ValueWriter { store: self, key, start, written: 0, pending: Vec::new(), committed: false };
+        writer.emit(&header)?;
+        Ok(writer)
+    }
+
+    /// Moves the value of `from` to `to`, overwriting any value at `to`.
+    /// Both records are appended in a single write and applied to the index
+    /// together. Returns false if `from` does not exist.
+    pub fn rename(&mut self, from: &str, to: String) -> Result<bool> {
+        self.check_writable()?;
+        let value = match self.get(from.to_owned())? {
+            Some(value) => value,
+            None => return Ok(false)
+        };
+        if from == to {
+            return Ok(true);
+        }
+
+        let op = self.set_op(to, value)?;
+        self.append(vec![op, Op::Rm(from.to_owned())])?;
+        self.maybe_compact()?;
+        Ok(true)
+    }
+
+    // builds the record for a set, validating and encoding the value as
+    // configured
+    fn set_op(&self, key: String, value: String) -> Result<Op> {
+        if let Some(ValueValidator(validator)) = &self.options.value_validator {
+            validator(&value).map_err(KvError::ValidationError)?;
+        }
+        let compression = self.options.compression;
+        let timestamp = now_millis();
+        let mut value = compression.encode(&value)?;
+        let blob = self.options.blob_threshold.is_some_and(|threshold| value.len() > threshold);
+        if blob {
+            value = self.write_blob(&value)?;
+        }
+        Ok(Op::SetWithMeta {
+            key,
+            value,
+            meta: RecordMeta { compression, timestamp, blob },
+        })
+    }
+
+    fn blob_dir(&self) -> path::PathBuf {
+        self.log_file.with_file_name(self.options.file_name(BLOB_DIR_NAME))
+    }
+
+    // stores an encoded value in a blob file named after its SHA-256, so
+    // equal values share a file, and returns the name
+    fn write_blob(&self, value: &str) -> Result<String> {
+        let name: String = Sha256::digest(value.as_bytes()).iter().map(|b| format!("{b:02x}")).collect();
+        let blobs = self.blob_dir();
+        let path = blobs.join(&name);
+        if !path.exists() {
+            fs::create_dir_all(&blobs)?;
+            // written aside and renamed, so a blob file is never seen partly written
+            let tmp_file = blobs.join(format!("{name}.tmp"));
+            let mut file = self.options.log_open_options().write(true).create(true).truncate(true).open(&tmp_file)?;
+            file.write_all(value.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp_file, &path)?;
+        }
+        Ok(name)
+    }
+
+    // deletes the blob files no record in `content` refers to
+    fn remove_unused_blobs(&self, content: &str) -> Result<()> {
+        let blobs = self.blob_dir();
+        if !blobs.is_dir() {
+            return Ok(());
+        }
+        // an open iterator may still read blobs of the old log; they are
+        // removed by a later compaction once it is dropped
+        let open_iters = Arc::strong_count(&self.snapshots) - 1;
+        if open_iters > 0 {
+            log::debug!("keeping unused blobs of {} for {open_iters} open iterators", self.log_file.display());
+            return Ok(());
+        }
+        let mut used = BTreeSet::new();
+        for line in content.lines() {
+            if let Op::SetWithMeta { value, meta: RecordMeta { blob: true, .. }, .. } = serde_json::from_str(line)? {
+                used.insert(value);
+            }
+        }
+        for entry in fs::read_dir(&blobs)? {
+            let entry = entry?;
+            if !entry.file_name().to_str().is_some_and(|name| used.contains(name)) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    // appends the ops to the log with a single write, then applies them to
+    // the index; if the write fails the index is untouched and the log is
+    // cut back to where it was
+    fn append(&mut self, ops: Vec<Op>) -> Result<()> {
+        let start = self.log_size;
+
+        let mut buf = Vec::new();
+        let mut entries = Vec::with_capacity(ops.len());
+        for op in ops.iter() {
+            let offset = start + buf.len() as u64;
+            serde_json::to_writer(&mut buf, op)?;
+            buf.push(b'\n');
+            let len = start + buf.len() as u64 - offset;
+            self.check_fits(len)?;
+            entries.push((offset, record_len(len)?, self.indexed_value(op)?));
+        }
+
+        let flush = self.flush_due();
+        let writer = self.writer.as_ref().ok_or(KvError::ReadOnlyError)?;
+        let written = {
+            let retries = self.options.io_retries;
+            let mut writer = lock_writer(writer);
+            write_all_retrying(&mut *writer, &buf, retries).and_then(|()| {
+                if flush {
+                    // BufWriter tracks what it already wrote, so flush can be retried
+                    retry_io(retries, || Ok(writer.flush()?))?;
+                }
+                Ok(())
+            })
+        };
+        if let Err(e) = written {
+            // a short write may have left part of a record behind
+            if let Err(cleanup) = self.truncate_log(start) {
+                log::warn!("failed to remove partial write from {}: {cleanup}", self.log_file.display());
+            }
+            return Err(e);
+        }
+        self.log_size += buf.len() as u64;
+        self.record_count += ops.len();
+
+        for (op, (offset, len, value)) in ops.into_iter().zip(entries) {
+            self.apply_to_index(op, offset, len, value);
+        }
+        Ok(())
+    }
+
+    // `value` is the decoded value of a `Set`, when the value index wants it
+    fn apply_to_index(&mut self, op: Op, offset: u64, len: u32, value: Option<String>) {
+        match op {
+            Op::Set(k, _) | Op::SetWithMeta { key: k, .. } => {
+                if self.options.key_filter.as_ref().is_some_and(|KeyFilter(pred)| !pred(&k)) {
+                    return;
+                }
+                if let (Some(values), Some(value)) = (&mut self.value_index, value) {
+                    values.insert(k.clone(), value);
+                }
+                self.index_set(k, offset, len);
+            },
+            Op::Rm(k) => {
+                if let Some(values) = &mut self.value_index {
+                    values.remove(&k);
+                }
+                // the tombstone goes at the next compaction along with the set
+                let removed = self.index.remove(&k).map_or(0, |(_, removed)| u64::from(removed));
+                self.dead_bytes += removed + u64::from(len);
+            },
+            _ => {
+                self.dead_bytes += u64::from(len);
+            }
+        }
+    }
+
+    // the decoded value of a `Set` op for the value index, if there is one
+    fn indexed_value(&self, op: &Op) -> Result<Option<String>> {
+        if self.value_index.is_none() {
+            return Ok(None);
+        }
+        match op {
+            Op::Set(_, value) => Ok(Some(value.clone())),
+            Op::SetWithMeta { value, meta, .. } => decode_value(&self.blob_dir(), value.clone(), meta).map(Some),
+            _ => Ok(None)
+        }
+    }
+
+    // points `key` at its new record, counting the one it replaces as dead
+    fn index_set(&mut self, key: String, offset: u64, len: u32) {
+        if let Some((_, replaced)) = self.index.insert(key, (offset, len)) {
+            self.dead_bytes += u64::from(replaced);
+        }
+    }
+
+    /// Rebuilds the index and log size by rescanning the log, picking up
+    /// records appended by another writer since this handle was opened.
+    pub fn reload_index(&mut self) -> Result<()> {
+        self.index.clear();
+        if let Some(values) = &mut self.value_index {
+            values.clear();
+        }
+        self.dead_bytes = 0;
+        self.invalidate_map();
+        self.load_index(&mut |_, _| ())?;
+        self.position_writer()
+    }
+
+    fn construct_index(mut self, on_progress: &mut dyn FnMut(u64, u64)) -> Result<Self> {
+        self.load_index(on_progress)?;
+        self.position_writer()?;
+        log::info!("opened {} with {} keys ({} bytes)", self.log_file.display(), self.index.len(), self.log_size);
+        Ok(self)
+    }
+
+    fn load_index(&mut self, on_progress: &mut dyn FnMut(u64, u64)) -> Result<()> {
+        self.log_base = read_log_base(&self.base_file())?;
+        let mut offset = 0;
+        let mut records = 0;
+        let mut file_handle = self.open_log()?;
+        let total = file_handle.metadata()?.len();
+        let mut buf_reader = io::BufReader::new( &mut file_handle);
+        while let Some(content) = read_record(&mut buf_reader, offset)? {
+            // parse line
+            let op = serde_json::from_slice::<Op>(content.as_bytes())?;
+            let value = self.indexed_value(&op)?;
+            self.apply_to_index(op, offset, record_len(content.len() as u64)?, value);
+
+            offset += content.len() as u64;
+            records += 1;
+            on_progress(offset, total);
+        }
+        self.log_size = offset;
+        self.record_count = records;
+
+        Ok(())
+    }
+
+    /// Syncs the log to disk and releases the directory lock. Unlike
+    /// dropping the store, errors are reported to the caller. With
+    /// `KvStoreBuilder::compact_on_close` the log is compacted first.
+    pub fn close(mut self) -> Result<()> {
+        if let Some(flusher) = self.flusher.take() {
+            flusher.stop();
+        }
+        if self.options.compact_on_close && self.writer.is_some() {
+            self.compact_now()?;
+        }
+        if let Some(lock) = self.lock.take() {
+            self.flush_writer()?;
+            File::open(&self.log_file)?.sync_all()?;
+            lock.unlock()?;
+        }
+        if let Some(lock) = self.reader_lock.take() {
+            lock.unlock()?;
+        }
+        Ok(())
+    }
+
+    // cuts the log back to `end`, dropping any records after it; records
+    // before `end` still sitting in the write buffer are kept
+    fn truncate_log(&self, end: u64) -> Result<()> {
+        let Some(writer) = &self.writer else {
+            return Ok(());
+        };
+        // a mapping reaching past the new end would fault on access
+        self.invalidate_map();
+        {
+            let mut writer = lock_writer(writer);
+            let fresh = BufWriter::new(writer.get_ref().try_clone()?);
+            let (mut file, buffered) = std::mem::replace(&mut *writer, fresh).into_parts();
+            let buffered = buffered.unwrap_or_else(|e| e.into_inner());
+            // the buffer holds the bytes meant for the file from its position on
+            let pos = file.stream_position()?;
+            if pos < end {
+                let keep = (end - pos).min(buffered.len() as u64) as usize;
+                file.write_all(&buffered[..keep])?;
+            }
+            file.set_len(end)?;
+        }
+        self.position_writer()
+    }
+
+    // drops the memory map of a log that may have been replaced
+    fn invalidate_map(&self) {
+        #[cfg(feature = "mmap")]
+        if let Some(log_map) = &self.log_map {
+            log_map.invalidate();
+        }
+    }
+
+    // moves the writer to the end of the records, padding the log out to
+    // its preallocated size first
+    fn position_writer(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            let mut writer = lock_writer(writer);
+            writer.flush()?;
+            let reserve = self.options.preallocate_bytes;
+            if writer.get_ref().metadata()?.len() < reserve {
+                writer.get_ref().set_len(reserve)?;
+            }
+            writer.seek(io::SeekFrom::Start(self.log_size))?;
+        }
+        Ok(())
+    }
+
+    fn flush_writer(&self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            lock_writer(writer).flush()?;
+        }
+        Ok(())
+    }
+
+    // counts a write and says whether it should be flushed right away
+    fn flush_due(&mut self) -> bool {
+        if self.options.flush_interval.is_some() {
+            return false;
+        }
+        self.unflushed_writes += 1;
+        if self.unflushed_writes >= self.options.flush_every {
+            self.unflushed_writes = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // opens the log for reading; buffered writes are flushed first so that
+    // they are visible to the reader. A read-only handle fails with
+    // `LogReplacedError` once the writer has swapped in a new log, since its
+    // offsets point into the old one
+    fn open_log(&self) -> Result<File> {
+        self.flush_writer()?;
+        self.log_opens.fetch_add(1, Ordering::Relaxed);
+        let file = File::open(&self.log_file)?;
+        if self.log_id.is_some() && file_id(&file)? != self.log_id {
+            return Err(KvError::LogReplacedError);
+        }
+        Ok(file)
+    }
+
+    /// Moves a read-only handle to the log its writer swapped in by
+    /// compacting, locking it and rebuilding the index from it. Returns
+    /// `false` without doing anything if the log was not replaced; records
+    /// appended since opening are picked up by `reload_index` instead.
+    pub fn refresh(&mut self) -> Result<bool> {
+        if self.reader_lock.is_none() {
+            return Ok(false);
+        }
+        let log = File::open(&self.log_file)?;
+        let id = file_id(&log)?;
+        if id == self.log_id {
+            return Ok(false);
+        }
+        lock_outcome(log.try_lock_shared(), &self.log_file)?;
+        log::info!("{} was replaced, reopening it", self.log_file.display());
+        self.reader_lock = Some(log);
+        self.log_id = id;
+        self.reload_index()?;
+        Ok(true)
+    }
+
+    /// Returns how many times this handle has opened the log to read it.
+    /// Reads through a memory map are not counted.
+    pub fn log_opens(&self) -> u64 {
+        self.log_opens.load(Ordering::Relaxed)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            Err(KvError::ReadOnlyError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns every `Set`/`Rm` record at or after change position
+    /// `offset`, with its position, in log order. A replica starts at 0 and
+    /// then polls with `changes_end` as of its last poll, or the position
+    /// just past the last record it applied. Values kept in blob files are
+    /// read back into their records, so the records stand on their own.
+    ///
+    /// Change positions are log offsets counted from the first write to the
+    /// store: every compaction moves the positions of the new log past the
+    /// end of the old one, so they never repeat, even across reopening.
+    /// Until the first compaction they equal offsets in the log file.
+    ///
+    /// Fails with `OffsetGoneError` when `offset` is past `changes_end`,
+    /// does not start a record, or is a position in a log compaction has
+    /// since replaced. Position 0 always returns the whole current log.
+    pub fn changes_since(&self, offset: u64) -> Result<Vec<(u64, Op)>> {
+        // the offset in the current log file
+        let start = match offset {
+            0 => 0,
+            _ if offset <= self.log_base => return Err(KvError::OffsetGoneError(offset)),
+            _ => offset - self.log_base
+        };
+        if start > self.log_size {
+            return Err(KvError::OffsetGoneError(offset));
+        }
+
+        let file_handle = self.open_log()?;
+        let mut buf = BufReader::new(file_handle);
+        if start > 0 {
+            // a record always starts right after a newline
+            let mut prev = [0u8; 1];
+            buf.seek(io::SeekFrom::Start(start - 1))?;
+            buf.read_exact(&mut prev)?;
+            if prev[0] != b'\n' {
+                return Err(KvError::OffsetGoneError(offset));
+            }
+        }
+
+        let mut changes = Vec::new();
+        let mut offset = start;
+        while offset < self.log_size {
+            let line = match read_record(&mut buf, offset)? {
+                Some(line) => line,
+                None => break
+            };
+            let op = match serde_json::from_slice::<Op>(line.as_bytes())? {
+                Op::Get(_) => None,
+                // the follower has no copy of our blob files
+                Op::SetWithMeta { key, value, meta: meta @ RecordMeta { blob: true, .. } } => Some(Op::SetWithMeta {
+                    key,
+                    value: fs::read_to_string(self.blob_dir().join(value))?,
+                    meta: RecordMeta { blob: false, ..meta },
+                }),
+                op => Some(op)
+            };
+            if let Some(op) = op {
+                changes.push((self.log_base + offset, op));
+            }
+            offset += line.len() as u64;
+        }
+
+        Ok(changes)
+    }
+
+    /// Returns the change position just past the last record, where
+    /// `changes_since` picks up records written after this call.
+    pub fn changes_end(&self) -> u64 {
+        self.log_base + self.log_size
+    }
+
+    /// Writes a fully-compacted copy of the live dataset into `dest_dir` and
+    /// returns a store opened on it. The current store is left untouched, so
+    /// this doubles as a backup. Fails if `dest_dir` already holds a store.
+    /// The new store is opened with the same options as this one. The
+    /// output is deterministic: compacting the same live records always
+    /// writes the same bytes.
+    pub fn compact_into(&self, dest_dir: impl Into<path::PathBuf>) -> Result<KvStore> {
+        let dest_dir = dest_dir.into();
+        fs::create_dir_all(&dest_dir)?;
+
+        let (content, _) = self.live_records()?;
+        {
+            let mut file = self.options.log_open_options()
+                .write(true)
+                .create_new(true)
+                .open(dest_dir.join(self.options.file_name(LOG_FILE_NAME)))?;
+            file.write_all(content.as_bytes())?;
+            file.flush()?;
+        }
+        let blobs = self.blob_dir();
+        if blobs.is_dir() {
+            let dest_blobs = dest_dir.join(self.options.file_name(BLOB_DIR_NAME));
+            fs::create_dir_all(&dest_blobs)?;
+            for entry in fs::read_dir(&blobs)? {
+                let entry = entry?;
+                fs::copy(entry.path(), dest_blobs.join(entry.file_name()))?;
+            }
+        }
+
+        let store = self.options.clone().read_only(false).open(dest_dir)?;
+        store.remove_unused_blobs(&content)?;
+        Ok(store)
+    }
+
+    // reads the latest record of every live key, in write order, along with
+    // the offsets those records will have in the returned content; records
+    // are copied byte for byte, never re-serialized or re-stamped, so the
+    // content depends only on the live records. With `keep_versions` each
+    // key's older records come right before its latest one.
+    fn live_records(&self) -> Result<(String, BTreeMap<String, u64>)> {
+        if self.options.keep_versions > 1 {
+            return self.versioned_records(self.options.keep_versions);
+        }
+        // the index knows where each live record starts and ends, so they
+        // are copied byte for byte without being parsed
+        let mut content = String::new();
+        let mut offsets = BTreeMap::new();
+        let mut record = Vec::new();
+        let file_handle = self.open_log()?;
+        let mut buf = BufReader::new(file_handle);
+        for (key, (offset, len)) in self.entries_by_offset() {
+            offsets.insert(key.clone(), content.len() as u64);
+            buf.seek(io::SeekFrom::Start(*offset))?;
+            record.resize(*len as usize, 0);
+            match buf.read_exact(&mut record) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(KvError::DanglingIndexError(*offset)),
+                result => result?
+            }
+            if record.last() != Some(&b'\n') {
+                return Err(KvError::DanglingIndexError(*offset));
+            }
+            content.push_str(std::str::from_utf8(&record).map_err(|_| KvError::NonUtf8RecordError { offset: *offset })?);
+        }
+        Ok((content, offsets))
+    }
+
+    // the index entries in log order, which is the order their latest
+    // records were written in as long as compaction keeps that order
+    fn entries_by_offset(&self) -> Vec<(&String, &index::Entry)> {
+        let mut entries: Vec<_> = self.index.iter().collect();
+        entries.sort_unstable_by_key(|(_, (offset, _))| *offset);
+        entries
+    }
+
+    // like `live_records`, keeping up to `keep` of the latest records of each
+    // live key; needs a full scan, since the index only knows the latest
+    fn versioned_records(&self, keep: usize) -> Result<(String, BTreeMap<String, u64>)> {
+        let mut history: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut offset = 0;
+        let mut buf = BufReader::new(self.open_log()?);
+        while let Some(line) = read_record(&mut buf, offset)? {
+            offset += line.len() as u64;
+            match serde_json::from_slice::<Op>(line.as_bytes())? {
+                Op::Set(k, _) | Op::SetWithMeta { key: k, .. } => history.entry(k).or_default().push(line),
+                Op::Rm(k) => {
+                    history.remove(&k);
+                },
+                _ => ()
+            }
+        }
+
+        let mut content = String::new();
+        let mut offsets = BTreeMap::new();
+        for (key, (offset, _)) in self.entries_by_offset() {
+            let lines = history.get(key).ok_or(KvError::DanglingIndexError(*offset))?;
+            let (latest, older) = lines.split_last().expect("history entries are never empty");
+            for line in &older[older.len().saturating_sub(keep - 1)..] {
+                content.push_str(line);
+            }
+            offsets.insert(key.clone(), content.len() as u64);
+            content.push_str(latest);
+        }
+        Ok((content, offsets))
+    }
+
+    /// Returns the number of live keys.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns true if the store holds no live keys.
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Returns the size in bytes of the log replayed or written so far.
+    pub fn log_size(&self) -> u64 {
+        self.log_size
+    }
+
+    /// Returns the offset of the first record and the end of the last one,
+    /// flushing buffered writes so other readers of the file see them all.
+    /// The log has no header, so the first record is at 0; the end is the
+    /// file length unless the log is preallocated.
+    pub fn log_bounds(&self) -> Result<(u64, u64)> {
+        self.flush_writer()?;
+        Ok((0, self.log_size))
+    }
+
+    /// Returns the live key count, log size, dead bytes and compaction count
+    /// in one go.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            live_keys: self.index.len(),
+            log_size: self.log_size,
+            dead_bytes: self.dead_bytes,
+            compaction_count: self.compaction_count,
+        }
+    }
+
+    fn live_bytes(&self) -> u64 {
+        self.log_size - self.dead_bytes
+    }
+
+    /// Returns the bytes of the log taken by overwritten or removed values
+    /// and tombstones, which the next compaction would reclaim. Older
+    /// versions kept by `KvStoreBuilder::keep_versions` count too. Kept up
+    /// to date as the log is written, so this does not read the log.
+    pub fn dead_bytes(&self) -> u64 {
+        self.dead_bytes
+    }
+
+    /// Returns the bytes ever written to the log divided by the size of the
+    /// live records: how many bytes were written per byte still readable.
+    /// Every set, removal and compaction adds to the count, so churn and
+    /// frequent compaction both raise it; a compaction adds the live
+    /// records it rewrote. The count is the current log plus every log it
+    /// replaced, as kept for `changes_end`, so it survives reopening. A
+    /// store that never wrote reports 1.0; one with no live records,
+    /// infinity.
+    pub fn write_amplification(&self) -> f64 {
+        let written = self.changes_end();
+        if written == 0 {
+            return 1.0;
+        }
+        written as f64 / self.live_bytes() as f64
+    }
+
+    /// Returns a reader over the raw log as of now, e.g. to copy it out as
+    /// a backup. It reads through its own handle and stops at the current
+    /// end of the log, so later writes, the padding of a preallocated log
+    /// and compactions, which replace the file, do not show up in it.
+    pub fn log_reader(&self) -> Result<impl Read> {
+        Ok(self.open_log()?.take(self.log_size))
+    }
+
+    /// Checks the record the index holds for `key` without scanning the
+    /// log: it must still be a set of `key`, of the indexed length, whose
+    /// value decodes. Records carry no checksum, so damage that leaves all
+    /// of that intact goes unnoticed. Returns false for a missing key or a
+    /// damaged record; only failing to read the log is an error.
+    pub fn verify_key(&self, key: &str) -> Result<bool> {
+        let Some((offset, len)) = self.index.get(key) else {
+            return Ok(false);
+        };
+        let mut buf_reader = io::BufReader::new(self.open_log()?);
+        buf_reader.seek(io::SeekFrom::Start(*offset))?;
+        let mut line = Vec::new();
+        buf_reader.read_until(b'\n', &mut line)?;
+        if line.len() != *len as usize {
+            return Ok(false);
+        }
+        let (k, value, meta) = match serde_json::from_slice::<Op>(&line) {
+            Ok(Op::Set(k, v)) => (k, v, RecordMeta::default()),
+            Ok(Op::SetWithMeta { key: k, value, meta }) => (k, value, meta),
+            _ => return Ok(false)
+        };
+        Ok(k == key && decode_value(&self.blob_dir(), value, &meta).is_ok())
+    }
+
+    /// Returns the log offset of the live record for `key`.
+    pub fn offset_of(&self, key: &str) -> Option<u64> {
+        self.index.get(key).map(|(offset, _)| *offset)
+    }
+
+    /// Returns the keys currently holding exactly `value`, in key order.
+    /// Always empty unless the store was opened with
+    /// `KvStoreBuilder::value_index`.
+    pub fn keys_with_value(&self, value: &str) -> Vec<String> {
+        self.value_index.as_ref().map_or_else(Vec::new, |values| values.keys_with(value).cloned().collect())
+    }
+
+    /// Returns the size in bytes of the live record for `key` in the log,
+    /// including its trailing newline, or `None` if the key is missing. The
+    /// index keeps record lengths, so nothing is read. A value moved out to
+    /// a blob file is not counted, only the record naming it.
+    pub fn key_disk_size(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self.index.get(key).map(|(_, len)| u64::from(*len)))
+    }
+
+    /// Iterates over live keys and their log offsets, in key order.
+    pub fn index_entries(&self) -> impl Iterator<Item = (&String, u64)> {
+        self.index.iter().map(|(k, (offset, _))| (k, *offset))
+    }
+
+    /// Returns a copy of the index as (key, offset) pairs in key order.
+    /// Unlike `index_entries` it does not borrow the store, so it can be
+    /// kept across later writes.
+    pub fn index_snapshot(&self) -> Vec<(String, u64)> {
+        self.index_entries().map(|(k, offset)| (k.clone(), offset)).collect()
+    }
+
+    /// Rebuilds the index into a freshly allocated map, releasing memory
+    /// left behind by many removals. Compaction already installs a freshly
+    /// built index, so this is only needed between compactions.
+    pub fn shrink_index(&mut self) {
+        self.index.shrink();
+    }
+
+    /// Estimates the heap and inline memory held by the index, in bytes. This
+    /// is an approximation: key bytes plus a fixed per-entry cost for the
+    /// `String` header, the offset and BTreeMap node overhead. Allocator
+    /// slack and spare `String` capacity are not counted.
+    pub fn index_memory_estimate(&self) -> usize {
+        let per_entry = std::mem::size_of::<String>() + std::mem::size_of::<(u64, u32)>() + BTREE_ENTRY_OVERHEAD;
+        self.index.keys().map(|k| k.len() + per_entry).sum()
+    }
+
+    /// Returns true when the log has grown enough that the next write will
+    /// trigger a compaction.
+    pub fn needs_compaction(&self) -> bool {
+        (self.log_size >= self.options.compaction_threshold && !self.cooling_down()) || self.over_capacity()
+    }
+
+    /// Returns how many times this handle has compacted the log.
+    pub fn compaction_count(&self) -> u64 {
+        self.compaction_count
+    }
+
+    fn cooling_down(&self) -> bool {
+        match (self.options.compaction_cooldown, self.last_compaction) {
+            (Some(cooldown), Some(last)) => last.elapsed() < cooldown,
+            _ => false
+        }
+    }
+
+    // a record over `max_total_bytes` would be evicted as soon as written
+    fn check_fits(&self, len: u64) -> Result<()> {
+        match self.options.max_total_bytes {
+            Some(max) if len > max => Err(KvError::RecordTooLargeError { len, max }),
+            _ => Ok(())
+        }
+    }
+
+    fn over_capacity(&self) -> bool {
+        self.options.max_total_bytes.is_some_and(|max| self.log_size > max)
+    }
+
+    /// Rewrites the log down to its live records now, whether or not it has
+    /// reached the compaction threshold, and reports what that changed.
+    pub fn compact(&mut self) -> Result<CompactionSummary> {
+        self.check_writable()?;
+        self.compact_now()
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+
+        if self.needs_compaction() {
+            self.compact_now()?;
+        }
+        Ok(())
+    }
+
+    // rewrites the log down to its live records whether or not it is due
+    fn compact_now(&mut self) -> Result<CompactionSummary> {
+        let started = self.start_compaction();
+        let (mut content, mut offsets) = self.live_records()?;
+        if let Some(max) = self.options.max_total_bytes {
+            if content.len() as u64 > max {
+                (content, offsets) = self.evict_oldest(&content, &offsets, max);
+            }
+        }
+        self.rewrite_log(content, offsets, started)
+    }
+
+    // drops the least recently written records from compacted `content`
+    // until it fits in `max` bytes; compaction keeps records in write order,
+    // so the oldest keys come first
+    fn evict_oldest(&self, content: &str, offsets: &BTreeMap<String, u64>, max: u64) -> (String, BTreeMap<String, u64>) {
+        let mut by_age: Vec<(&String, u64)> = offsets.iter().map(|(k, offset)| (k, *offset)).collect();
+        by_age.sort_unstable_by_key(|(_, offset)| *offset);
+
+        let mut size = content.len() as u64;
+        let mut evicted = 0;
+        let mut kept = String::new();
+        let mut kept_offsets = BTreeMap::new();
+        // each key's records end with its latest one and are followed by the
+        // next key's, so a key spans from the previous key's end to its own
+        let mut start = 0;
+        for (k, offset) in by_age {
+            let rest = &content[offset as usize..];
+            let end = offset + rest.find('\n').map_or(rest.len(), |i| i + 1) as u64;
+            if size > max {
+                size -= end - start;
+                evicted += 1;
+            } else {
+                kept_offsets.insert(k.clone(), kept.len() as u64 + offset - start);
+                kept.push_str(&content[start as usize..end as usize]);
+            }
+            start = end;
+        }
+        log::info!("evicting {} keys from {} to fit {} bytes", evicted, self.log_file.display(), max);
+        (kept, kept_offsets)
+    }
+
+    /// Compacts the log without trusting the index: the live set is
+    /// recovered by replaying the whole log, the log is rewritten from it and
+    /// the index is rebuilt from scratch. Use when the index is suspected to
+    /// be stale.
+    pub fn compact_from_scan(&mut self) -> Result<()> {
+        self.check_writable()?;
+        log::warn!("recovering live set of {} from a full log scan", self.log_file.display());
+        let started = self.start_compaction();
+
+        let mut live = BTreeMap::new();
+        let mut offset = 0;
+        let mut buf = BufReader::new(self.open_log()?);
+        while let Some(line) = read_record(&mut buf, offset)? {
+            offset += line.len() as u64;
+            match serde_json::from_slice::<Op>(line.as_bytes())? {
+                Op::Set(k, _) | Op::SetWithMeta { key: k, .. } => {
+                    live.insert(k, (offset, line));
+                },
+                Op::Rm(k) => {
+                    live.remove(&k);
+                },
+                _ => ()
+            }
+        }
+
+        // in write order, as `live_records` does
+        let mut live: Vec<_> = live.into_iter().collect();
+        live.sort_unstable_by_key(|(_, (offset, _))| *offset);
+        let mut content = String::new();
+        let mut offsets = BTreeMap::new();
+        for (k, (_, line)) in live {
+            offsets.insert(k, content.len() as u64);
+            content.push_str(&line);
+        }
+        self.rewrite_log(content, offsets, started)?;
+        Ok(())
+    }
+
+    // tells the observer a compaction is starting and returns its start time
+    fn start_compaction(&self) -> Instant {
+        if let Some(ObserverHandle(observer)) = &self.options.compaction_observer {
+            observer.on_start(self.index.len());
+        }
+        Instant::now()
+    }
+
+    // replaces the log with `content`, whose records are at `offsets`, for a
+    // compaction that began at `started`
+    fn rewrite_log(&mut self, content: String, offsets: BTreeMap<String, u64>, started: Instant) -> Result<CompactionSummary> {
+        let index = offsets.into_iter()
+            .map(|(key, offset)| {
+                let rest = &content[offset as usize..];
+                let len = rest.find('\n').map_or(rest.len(), |i| i + 1);
+                Ok((key, (offset, record_len(len as u64)?)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let index = Index::from_entries(self.index.kind(), index);
+        self.swap_log(&content)?;
+        self.remove_unused_blobs(&content)?;
+
+        let summary = CompactionSummary {
+            records_before: self.record_count,
+            records_after: content.lines().count(),
+            bytes_before: self.log_size,
+            bytes_after: content.len() as u64,
+            duration: started.elapsed(),
+        };
+        log::info!("compacted {} from {} to {} bytes", self.log_file.display(), self.log_size, content.len());
+        if let Some(ObserverHandle(observer)) = &self.options.compaction_observer {
+            observer.on_finish(summary.bytes_before, summary.bytes_after, summary.duration);
+        }
+        // a new map, so memory held by removed keys is released as well
+        self.index = index;
+        if let Some(values) = &mut self.value_index {
+            // eviction may have dropped keys
+            values.retain(|key| self.index.contains_key(key));
+        }
+        self.log_size = summary.bytes_after;
+        self.record_count = summary.records_after;
+        // older versions kept by `keep_versions` are dead, as on replay
+        let live: u64 = self.index.iter().map(|(_, (_, len))| u64::from(*len)).sum();
+        self.dead_bytes = self.log_size - live;
+        self.invalidate_map();
+        self.last_compaction = Some(Instant::now());
+        self.compaction_count += 1;
+        self.position_writer()?;
+        Ok(summary)
+    }
+
+    // replaces the log file with one holding `content` and points the
+    // writer at it, leaving the index and log size to the caller
+    //
+    // the new log is written beside the old one and renamed over it, so
+    // readers holding the old file (see `iter`) keep a consistent view
+    //
+    // the base moves past the old log first: a crash before the rename then
+    // only makes positions in the old log gone early, never reused
+    fn swap_log(&mut self, content: &str) -> Result<()> {
+        let writer = self.writer.as_ref().ok_or(KvError::ReadOnlyError)?;
+        let mut writer = lock_writer(writer);
+        writer.flush()?;
+        let base = self.log_base + self.log_size;
+        write_log_base(&self.base_file(), base)?;
+
+        let mut tmp_name = self.log_file.clone().into_os_string();
+        tmp_name.push(".compact");
+        let tmp_file = path::PathBuf::from(tmp_name);
+        {
+            // a leftover from an interrupted compaction would keep its old mode
+            if tmp_file.exists() {
+                fs::remove_file(&tmp_file)?;
+            }
+            let mut file = self.options.log_open_options().write(true).create_new(true).open(&tmp_file)?;
+            file.write_all(content.as_bytes())?;
+            file.flush()?;
+        }
+        fs::rename(&tmp_file, &self.log_file)?;
+        let file = self.options.open_log_writer(&self.log_file)?;
+        *writer = BufWriter::new(file);
+        self.log_base = base;
+        Ok(())
+    }
+
+    fn base_file(&self) -> path::PathBuf {
+        self.log_file.with_file_name(self.options.file_name(BASE_FILE_NAME))
+    }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        if let Some(flusher) = self.flusher.take() {
+            flusher.stop();
+        }
+        if let Err(e) = self.flush_writer() {
+            log::warn!("failed to flush {} on drop: {e}", self.log_file.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use super::{retry_io, write_all_retrying};
+
+    // accepts at most `chunk` bytes per write, after failing with `kind`
+    // before each of the first `failures` writes
+    struct FlakyWriter {
+        failures: usize,
+        kind: io::ErrorKind,
+        chunk: usize,
+        calls: usize,
+        written: Vec<u8>,
+    }
+
+    impl FlakyWriter {
+        fn new(failures: usize, kind: io::ErrorKind, chunk: usize) -> Self {
+            FlakyWriter { failures, kind, chunk, calls: 0, written: Vec::new() }
+        }
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from(self.kind));
+            }
+            let n = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // `retry_io` should retry transient errors a bounded number of times
+    // and fail immediately on other errors.
+    #[test]
+    fn retry_io_transient_errors() {
+        let mut writer = FlakyWriter::new(2, io::ErrorKind::WouldBlock, usize::MAX);
+        retry_io(3, || Ok(writer.write(b"record")?)).unwrap();
+        assert_eq!(writer.written, b"record");
+        assert_eq!(writer.calls, 3);
+
+        let mut writer = FlakyWriter::new(2, io::ErrorKind::Interrupted, usize::MAX);
+        assert!(retry_io(1, || Ok(writer.write(b"record")?)).is_err());
+        assert_eq!(writer.calls, 2);
+
+        let mut writer = FlakyWriter::new(1, io::ErrorKind::PermissionDenied, usize::MAX);
+        assert!(retry_io(3, || Ok(writer.write(b"record")?)).is_err());
+        assert_eq!(writer.calls, 1);
+    }
+
+    // `write_all_retrying` should finish a record written in pieces across
+    // transient errors without repeating bytes, and give up past its
+    // retries or on other errors.
+    #[test]
+    fn write_all_retrying_partial_writes() {
+        let mut writer = FlakyWriter::new(0, io::ErrorKind::Interrupted, 4);
+        write_all_retrying(&mut writer, b"a longer record", 0).unwrap();
+        assert_eq!(writer.written, b"a longer record");
+        assert_eq!(writer.calls, 4);
+
+        let mut writer = FlakyWriter::new(2, io::ErrorKind::Interrupted, 4);
+        write_all_retrying(&mut writer, b"a longer record", 2).unwrap();
+        assert_eq!(writer.written, b"a longer record");
+        assert_eq!(writer.calls, 6);
+
+        let mut writer = FlakyWriter::new(3, io::ErrorKind::WouldBlock, 4);
+        assert!(write_all_retrying(&mut writer, b"a longer record", 2).is_err());
+        assert_eq!(writer.calls, 3);
+        assert!(writer.written.is_empty());
+
+        let mut writer = FlakyWriter::new(1, io::ErrorKind::PermissionDenied, 4);
+        assert!(write_all_retrying(&mut writer, b"a longer record", 3).is_err());
+        assert_eq!(writer.calls, 1);
+
+        let mut writer = FlakyWriter::new(0, io::ErrorKind::Interrupted, 0);
+        assert!(write_all_retrying(&mut writer, b"record", 3).is_err());
+    }
+}
@@ -1,8 +1,8 @@
 extern crate kvs;
 
-use std::process::exit;
+use std::{fs::File, io::{BufReader, BufWriter}, path::PathBuf, process::exit};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use kvs::KvError;
 use serde::{Serialize, Deserialize};
 
@@ -11,7 +11,22 @@ use serde::{Serialize, Deserialize};
 #[command(propagate_version=true)]
 struct Cli {
     #[command(subcommand)]
-    command: Commands
+    command: Commands,
+    /// Report a missing key on stderr and exit with code 2 instead of
+    /// printing "Key not found" to stdout
+    #[arg(long, global = true)]
+    quiet: bool,
+    #[arg(long, global = true, value_enum, default_value_t = Format::Plain)]
+    format: Format,
+    /// Directory holding the store; defaults to the current directory
+    #[arg(long, global = true, env = "TOYCASK_DIR")]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,ValueEnum)]
+enum Format {
+    Plain,
+    Json,
 }
 
 #[derive(Debug,Subcommand,Serialize,Deserialize)]
@@ -19,14 +34,20 @@ enum Commands {
     Get{key: String},
     Set{key: String, value: String},
     Rm{key: String},
+    Export{#[arg(long)] path: PathBuf},
+    Import{path: PathBuf},
+    Stats{#[arg(long)] json: bool},
 }
 
-impl Into<kvs::Op> for Commands {
-    fn into(self) -> kvs::Op {
-        match self {
-            Self::Get { key } => kvs::Op::Get(key),
-            Self::Set { key, value } => kvs::Op::Set(key, value),
-            Self::Rm { key } => kvs::Op::Rm(key)
+impl TryFrom<Commands> for kvs::Op {
+    type Error = KvError;
+
+    fn try_from(value: Commands) -> Result<Self, Self::Error> {
+        match value {
+            Commands::Get { key } => Ok(kvs::Op::Get(key)),
+            Commands::Set { key, value } => Ok(kvs::Op::Set(key, value)),
+            Commands::Rm { key } => Ok(kvs::Op::Rm(key)),
+            Commands::Export { .. } | Commands::Import { .. } | Commands::Stats { .. } => Err(KvError::InvalidCommandError)
         }
     }
 }
@@ -34,15 +55,30 @@ impl Into<kvs::Op> for Commands {
 fn main() {
     let cli = Cli::parse();
 
-    let dir = std::env::current_dir().unwrap();
+    let dir = cli.dir.unwrap_or_else(|| std::env::current_dir().unwrap());
     let mut store = kvs::KvStore::open(dir).unwrap();
     match cli.command  {
         Commands::Get { key } => {
-            let result = store.get(key).unwrap();
+            let result = store.get(key.clone()).unwrap();
+            if cli.format == Format::Json {
+                let output = match &result {
+                    Some(value) => serde_json::json!({"key": key, "value": value}),
+                    None => serde_json::json!({"key": key, "found": false})
+                };
+                println!("{output}");
+                if result.is_none() && cli.quiet {
+                    exit(2);
+                }
+                return;
+            }
             match result {
                 Some(value) => {
                     println!("{value}");
                 },
+                None if cli.quiet => {
+                    eprintln!("Key not found");
+                    exit(2);
+                },
                 None => {
                     println!("Key not found");
                 }
@@ -62,6 +98,35 @@ fn main() {
         },
         Commands::Set { key, value } => {
             store.set(key, value).unwrap();
+        },
+        Commands::Export { path } => {
+            let result = File::create(path)
+                .map_err(KvError::from)
+                .and_then(|file| store.export_json(BufWriter::new(file)));
+            if let Err(e) = result {
+                eprintln!("{e}");
+                exit(1);
+            }
+        },
+        Commands::Import { path } => {
+            let result = File::open(path)
+                .map_err(KvError::from)
+                .and_then(|file| store.import_json(BufReader::new(file)));
+            if let Err(e) = result {
+                eprintln!("{e}");
+                exit(1);
+            }
+        },
+        Commands::Stats { json } => {
+            let stats = store.stats();
+            if json {
+                println!("{}", serde_json::to_string(&stats).unwrap());
+            } else {
+                println!("live keys         {}", stats.live_keys);
+                println!("log size          {}", stats.log_size);
+                println!("dead bytes        {}", stats.dead_bytes);
+                println!("compactions       {}", stats.compaction_count);
+            }
         }
     }
 }
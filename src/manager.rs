@@ -0,0 +1,40 @@
+use std::{collections::BTreeMap, fs, path::{Component, Path, PathBuf}};
+
+use crate::{KvError, KvStore, KvStoreBuilder, Result};
+
+/// Opens named stores in subdirectories of a root directory on first use
+/// and keeps them open. Each store holds its own lock, so a name already
+/// open in another process fails with `LockedError`.
+pub struct StoreManager {
+    root: PathBuf,
+    options: KvStoreBuilder,
+    stores: BTreeMap<String, KvStore>,
+}
+
+impl StoreManager {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self::with_options(root, KvStoreBuilder::default())
+    }
+
+    /// Opens every store with `options`.
+    pub fn with_options(root: impl Into<PathBuf>, options: KvStoreBuilder) -> Self {
+        StoreManager { root: root.into(), options, stores: BTreeMap::new() }
+    }
+
+    /// Returns the store called `name`, opening it in `<root>/<name>` (and
+    /// creating that directory) if this manager has not opened it yet.
+    /// `name` must be a single path component.
+    pub fn get_store(&mut self, name: &str) -> Result<&mut KvStore> {
+        if !self.stores.contains_key(name) {
+            let mut components = Path::new(name).components();
+            let dir = match (components.next(), components.next()) {
+                (Some(Component::Normal(dir)), None) if dir == name => self.root.join(dir),
+                _ => return Err(KvError::InvalidStoreNameError(name.to_owned()))
+            };
+            fs::create_dir_all(&dir)?;
+            let store = self.options.clone().open(dir)?;
+            self.stores.insert(name.to_owned(), store);
+        }
+        Ok(self.stores.get_mut(name).expect("store opened above"))
+    }
+}
@@ -0,0 +1,43 @@
+use std::{fs::File, sync::Mutex};
+
+use memmap2::Mmap;
+
+use crate::{parse_set, KvError, RecordMeta, Result};
+
+// a read-only mapping of the log, remapped when a read goes past its end
+#[derive(Default)]
+pub(crate) struct LogMap(Mutex<Option<Mmap>>);
+
+impl LogMap {
+    // parses the `Set` record for `key` at `offset` in place, mapping the
+    // file returned by `open` if there is no mapping yet or the record does
+    // not end inside the current one
+    pub(crate) fn read_set_at(&self, open: impl Fn() -> Result<File>, key: &str, offset: u64) -> Result<(String, RecordMeta)> {
+        let mut map = self.0.lock().expect("log map lock poisoned");
+        for remap in [map.is_none(), true] {
+            if remap {
+                // SAFETY: records the index points at are never rewritten in
+                // place. Past them the store only writes into preallocated
+                // padding, which no read parses until a record ends there,
+                // and cuts off partial records with `truncate_log`, which
+                // invalidates the map first so no mapped page outlives the end
+                // of the file; replaced logs are renamed over, leaving the
+                // mapped file intact. Truncating the file from outside the
+                // store while it is mapped is not supported
+                *map = Some(unsafe { Mmap::map(&open()?)? });
+            }
+            let bytes = map.as_deref().expect("mapped above");
+            let record = bytes.get(offset as usize..)
+                .and_then(|rest| rest.iter().position(|b| *b == b'\n').map(|end| &rest[..=end]));
+            if let Some(record) = record {
+                return parse_set(record, key, offset);
+            }
+        }
+        Err(KvError::DanglingIndexError(offset))
+    }
+
+    // drops the mapping, e.g. after the log file was replaced
+    pub(crate) fn invalidate(&self) {
+        *self.0.lock().expect("log map lock poisoned") = None;
+    }
+}
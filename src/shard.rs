@@ -0,0 +1,236 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::{KvStore, KvStoreBuilder, Result};
+
+/// Maps a key to the shard that stores it. Implemented for any
+/// `Fn(&str) -> usize`; results are taken modulo the number of shards.
+pub trait Shardable {
+    fn shard_for(&self, key: &str) -> usize;
+}
+
+impl<F: Fn(&str) -> usize> Shardable for F {
+    fn shard_for(&self, key: &str) -> usize {
+        self(key)
+    }
+}
+
+// points each shard takes on the ring; more points spread keys more evenly
+const RING_POINTS_PER_SHARD: usize = 64;
+
+/// Routes keys by consistent hashing: shards own points on a hash ring and
+/// each key goes to the shard owning the next point after the key's hash.
+/// Adding or removing a shard therefore only moves the keys of the ring
+/// segments it gains or gives up, about `1 / shards` of them, instead of
+/// nearly all keys as with a modulo router. Use it through
+/// `ShardedKvStore::add_shard` and `remove_shard`, which move those keys.
+///
+/// Ring points belong to shard ids rather than shard numbers, so removing
+/// a shard leaves the points of the others where they were. Ids start out
+/// equal to the shard numbers; once a shard other than the last has been
+/// removed they differ, and the router must be rebuilt with
+/// `with_ids(router.shard_ids())` to route the same way on reopen.
+#[derive(Debug, Clone)]
+pub struct ConsistentHashRouter {
+    // ring point to shard id
+    ring: BTreeMap<u64, usize>,
+    // shard id of each shard number
+    ids: Vec<usize>,
+}
+
+impl ConsistentHashRouter {
+    /// Creates a router over `shards` shards, which should be the number
+    /// of directories the store is opened with.
+    pub fn new(shards: usize) -> Self {
+        Self::with_ids(0..shards)
+    }
+
+    /// Creates a router whose shard numbers, in order, have the ids `ids`,
+    /// as returned by `shard_ids`. Panics if an id repeats.
+    pub fn with_ids(ids: impl IntoIterator<Item = usize>) -> Self {
+        let mut router = ConsistentHashRouter { ring: BTreeMap::new(), ids: Vec::new() };
+        for id in ids {
+            assert!(!router.ids.contains(&id), "shard id {id} is used twice");
+            router.push_shard(id);
+        }
+        router
+    }
+
+    /// Returns the id of every shard, in shard number order.
+    pub fn shard_ids(&self) -> &[usize] {
+        &self.ids
+    }
+
+    // takes up the ring points of a new last shard, under an id no shard
+    // has, and returns its number
+    fn add_shard(&mut self) -> usize {
+        self.push_shard(self.ids.iter().max().map_or(0, |id| id + 1));
+        self.ids.len() - 1
+    }
+
+    fn push_shard(&mut self, id: usize) {
+        for point in 0..RING_POINTS_PER_SHARD {
+            self.ring.insert(ring_hash(&format!("shard-{id}-{point}")), id);
+        }
+        self.ids.push(id);
+    }
+
+    // gives up the ring points of shard `n`; later shards move down a
+    // number but keep their ids, and with them their points
+    fn remove_shard(&mut self, n: usize) {
+        let id = self.ids.remove(n);
+        self.ring.retain(|_, shard| *shard != id);
+    }
+}
+
+impl Shardable for ConsistentHashRouter {
+    fn shard_for(&self, key: &str) -> usize {
+        let hash = ring_hash(key);
+        self.ring.range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .and_then(|(_, id)| self.ids.iter().position(|shard| shard == id))
+            .unwrap_or(0)
+    }
+}
+
+// a hash that stays the same across runs and builds, unlike std's
+fn ring_hash(s: &str) -> u64 {
+    let digest = Sha256::digest(s.as_bytes());
+    u64::from_le_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+/// Spreads keys over several `KvStore`s, each in its own directory, using
+/// a user supplied router. The router must stay the same across opens, or
+/// keys written earlier will be looked up in the wrong shard.
+pub struct ShardedKvStore<S: Shardable> {
+    shards: Vec<KvStore>,
+    router: S,
+    // used for shards added later
+    options: KvStoreBuilder,
+}
+
+impl<S: Shardable> ShardedKvStore<S> {
+    /// Opens one shard per directory, creating missing directories. Shard
+    /// numbers follow the order of `dirs`. Panics if `dirs` is empty.
+    pub fn open(dirs: impl IntoIterator<Item = impl Into<PathBuf>>, router: S) -> Result<Self> {
+        Self::open_with(KvStoreBuilder::default(), dirs, router)
+    }
+
+    /// Like `open`, opening every shard with `options`.
+    pub fn open_with(options: KvStoreBuilder, dirs: impl IntoIterator<Item = impl Into<PathBuf>>, router: S) -> Result<Self> {
+        let shards = dirs.into_iter()
+            .map(|dir| {
+                let dir = dir.into();
+                fs::create_dir_all(&dir)?;
+                options.clone().open(dir)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert!(!shards.is_empty(), "a sharded store needs at least one shard");
+        Ok(ShardedKvStore { shards, router, options })
+    }
+
+    /// Returns the number of the shard `key` is stored in.
+    pub fn shard_of(&self, key: &str) -> usize {
+        self.router.shard_for(key) % self.shards.len()
+    }
+
+    /// Returns the router keys are placed with.
+    pub fn router(&self) -> &S {
+        &self.router
+    }
+
+    /// Returns shard `n`, for inspecting it directly.
+    pub fn shard(&self, n: usize) -> Option<&KvStore> {
+        self.shards.get(n)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.shards[self.shard_of(&key)].get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let n = self.shard_of(&key);
+        self.shards[n].set(key, value)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let n = self.shard_of(&key);
+        self.shards[n].remove(key)
+    }
+
+    /// Returns the number of live keys across all shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(KvStore::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(KvStore::is_empty)
+    }
+
+    /// Returns the live keys of all shards, in key order.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.shards.iter()
+            .flat_map(|shard| shard.index_entries().map(|(key, _)| key.clone()))
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+}
+
+impl ShardedKvStore<ConsistentHashRouter> {
+    /// Opens a new last shard in `dir` and moves to it the keys the router
+    /// now sends there, returning how many moved. Each key is written to
+    /// its new shard before it is removed from the old one, so a failure
+    /// part way leaves some keys in both, never in neither, and reads
+    /// already go to the new shard.
+    pub fn add_shard(&mut self, dir: impl Into<PathBuf>) -> Result<usize> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        self.shards.push(self.options.clone().open(dir)?);
+        let n = self.router.add_shard();
+
+        let mut moved = 0;
+        for old in 0..n {
+            let keys: Vec<String> = self.shards[old].index_entries()
+                .map(|(key, _)| key.clone())
+                .filter(|key| self.router.shard_for(key) == n)
+                .collect();
+            for key in keys {
+                if let Some(value) = self.shards[old].get(key.clone())? {
+                    self.shards[n].set(key.clone(), value)?;
+                    self.shards[old].remove(key)?;
+                    moved += 1;
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Removes shard `n`, moving its keys to the shards the router now
+    /// sends them to, and returns it, empty. Only the removed shard's keys
+    /// move. Later shards are renumbered down by one but keep their ids;
+    /// see `ConsistentHashRouter` for reopening afterwards. Returns `None`
+    /// if there is no shard `n` or it is the only one. Every key is copied
+    /// to its new shard before shard `n` is taken out, so a failure part
+    /// way leaves some keys in both, never in neither, and reads still go
+    /// to shard `n`.
+    pub fn remove_shard(&mut self, n: usize) -> Result<Option<KvStore>> {
+        if n >= self.shards.len() || self.shards.len() == 1 {
+            return Ok(None);
+        }
+        let mut router = self.router.clone();
+        router.remove_shard(n);
+        for (key, value) in self.shards[n].iter()?.collect::<Result<Vec<_>>>()? {
+            // numbered as after the removal, so later shards are one higher
+            let to = router.shard_for(&key);
+            let to = if to < n { to } else { to + 1 };
+            self.shards[to].set(key, value)?;
+        }
+        self.router = router;
+        let mut removed = self.shards.remove(n);
+        removed.drain()?;
+        Ok(Some(removed))
+    }
+}
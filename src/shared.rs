@@ -0,0 +1,138 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, Instant}
+};
+
+use crate::{KvError, KvStore, Result};
+
+/// A `KvStore` that can be cloned and shared between threads. Reads take a
+/// shared lock and writes an exclusive one, so concurrent readers never see
+/// a write half-applied. Wrapping a read-only handle, reads follow the log
+/// to the new file when its writer compacts, see `KvStore::refresh`.
+#[derive(Clone)]
+pub struct SharedKvStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    store: RwLock<KvStore>,
+    // bumped after every write, so waiters can tell a write happened
+    generation: Mutex<u64>,
+    written: Condvar,
+    // keys currently held through `lock_key`
+    locked_keys: Mutex<HashSet<String>>,
+    key_released: Condvar,
+}
+
+/// Holds a key reserved by `SharedKvStore::lock_key` until dropped.
+pub struct KeyGuard {
+    inner: Arc<Inner>,
+    key: String,
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        self.inner.locked_keys.lock().expect("key lock poisoned").remove(&self.key);
+        self.inner.key_released.notify_all();
+    }
+}
+
+impl SharedKvStore {
+    pub fn new(store: KvStore) -> Self {
+        SharedKvStore {
+            inner: Arc::new(Inner {
+                store: RwLock::new(store),
+                generation: Mutex::new(0),
+                written: Condvar::new(),
+                locked_keys: Mutex::new(HashSet::new()),
+                key_released: Condvar::new(),
+            })
+        }
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.read_fresh(|store| store.get(key.clone()))
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.update(|store| store.set(key, value))
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.update(|store| store.remove(key))
+    }
+
+    /// Looks up all `keys` under one read lock, so no write can land between
+    /// two of the lookups.
+    pub fn snapshot_get(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.read_fresh(|store| store.snapshot_get(keys))
+    }
+
+    /// Runs `f` with exclusive access to the store, for updates that must
+    /// appear to readers all at once.
+    pub fn update<T>(&self, f: impl FnOnce(&mut KvStore) -> Result<T>) -> Result<T> {
+        let result = f(&mut self.write());
+        *self.inner.generation.lock().expect("store lock poisoned") += 1;
+        self.inner.written.notify_all();
+        result
+    }
+
+    /// Returns the value of `key`, blocking until another handle sets it if
+    /// it is missing. Returns `None` if `timeout` elapses first.
+    pub fn wait_for(&self, key: &str, timeout: Duration) -> Result<Option<String>> {
+        let deadline = Instant::now() + timeout;
+        let mut generation = self.inner.generation.lock().expect("store lock poisoned");
+        loop {
+            // writers bump the generation after releasing the store, so a
+            // write missed here still wakes the wait below
+            if let Some(value) = self.get(key.to_owned())? {
+                return Ok(Some(value));
+            }
+            let seen = *generation;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            generation = self.inner.written
+                .wait_timeout_while(generation, remaining, |generation| *generation == seen)
+                .expect("store lock poisoned")
+                .0;
+        }
+    }
+
+    /// Reserves `key`, blocking while another handle holds it, until the
+    /// returned guard is dropped. The lock is advisory: it only excludes
+    /// other `lock_key` calls for the same key, not reads or writes, and
+    /// leaves other keys and the store itself free.
+    pub fn lock_key(&self, key: &str) -> KeyGuard {
+        let mut locked = self.inner.key_released
+            .wait_while(self.inner.locked_keys.lock().expect("key lock poisoned"), |locked| locked.contains(key))
+            .expect("key lock poisoned");
+        locked.insert(key.to_owned());
+        KeyGuard { inner: self.inner.clone(), key: key.to_owned() }
+    }
+
+    // runs the read `f`, moving a read-only store to the new log and running
+    // it again if the writer replaced the log underneath it
+    fn read_fresh<T>(&self, f: impl Fn(&KvStore) -> Result<T>) -> Result<T> {
+        // bound first so the read lock is released before taking the write one
+        let result = f(&self.read());
+        match result {
+            Err(KvError::LogReplacedError) => {
+                let mut store = self.write();
+                store.refresh()?;
+                f(&store)
+            },
+            result => result
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, KvStore> {
+        self.inner.store.read().expect("store lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, KvStore> {
+        self.inner.store.write().expect("store lock poisoned")
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap};
+
+// the keys holding each live value, for `KvStore::keys_with_value`; the
+// value of every key is kept as well, so an overwrite can find the entry
+// of the value it replaces
+#[derive(Default)]
+pub(crate) struct ValueIndex {
+    keys_by_value: BTreeMap<String, BTreeSet<String>>,
+    values: HashMap<String, String>,
+}
+
+impl ValueIndex {
+    pub(crate) fn insert(&mut self, key: String, value: String) {
+        self.remove(&key);
+        self.keys_by_value.entry(value.clone()).or_default().insert(key.clone());
+        self.values.insert(key, value);
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) {
+        let Some(value) = self.values.remove(key) else {
+            return;
+        };
+        if let btree_map::Entry::Occupied(mut keys) = self.keys_by_value.entry(value) {
+            keys.get_mut().remove(key);
+            if keys.get().is_empty() {
+                keys.remove();
+            }
+        }
+    }
+
+    // keys holding `value`, in key order
+    pub(crate) fn keys_with(&self, value: &str) -> impl Iterator<Item = &String> {
+        self.keys_by_value.get(value).into_iter().flatten()
+    }
+
+    // drops every key `keep` returns false for
+    pub(crate) fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        let dropped: Vec<String> = self.values.keys().filter(|key| !keep(key)).cloned().collect();
+        for key in dropped {
+            self.remove(&key);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        *self = ValueIndex::default();
+    }
+}
@@ -0,0 +1,41 @@
+use std::collections::BTreeSet;
+
+use crate::Op;
+
+// splitmix64, small and good enough for generating workloads
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Generates `n` operations mixing sets and removes, reproducible for a given
+/// `seed`. Removes only target keys that are live at that point, so the
+/// workload can be applied to an empty store without `KeyNotFoundError`.
+pub fn random_workload(n: usize, seed: u64) -> Vec<Op> {
+    let mut rng = Rng(seed);
+    let key_space = (n / 4).max(1) as u64;
+    let mut live = BTreeSet::new();
+    let mut ops = Vec::with_capacity(n);
+
+    while ops.len() < n {
+        let key = format!("key{}", rng.next() % key_space);
+        // roughly one remove for every four sets
+        if rng.next().is_multiple_of(5) && live.contains(&key) {
+            live.remove(&key);
+            ops.push(Op::Rm(key));
+        } else {
+            let value = format!("value{}", rng.next());
+            live.insert(key.clone());
+            ops.push(Op::Set(key, value));
+        }
+    }
+
+    ops
+}
@@ -1,8 +1,11 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{key, parse_key, CompactionObserver, Compression, ConsistentHashRouter, IndexKind, KeyStatus, KvError, KvStore, OnDuplicate, Op, RepairReport, Result, ShardedKvStore, SharedKvStore, StoreManager};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::io::Write;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +20,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +31,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +44,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +57,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +75,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +83,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +103,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +111,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +124,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +139,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +160,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +175,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -191,7 +194,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -230,7 +233,7 @@ fn get_non_existent_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -289,7 +292,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -298,4 +301,2848 @@ fn compaction() -> Result<()> {
     }
 
     panic!("No compaction detected");
-}
\ No newline at end of file
+}
+
+// `compact_into` should write only live values into a fresh directory and
+// leave the source store untouched.
+#[test]
+fn compact_into_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let source_log = temp_dir.path().join("store");
+    let source_before = std::fs::read(&source_log)?;
+
+    let backup = store.compact_into(backup_dir.path().join("backup"))?;
+    for key_id in 1..10 {
+        let key = format!("key{}", key_id);
+        assert_eq!(backup.get(key.clone())?, Some("value9".to_owned()));
+        assert_eq!(store.get(key)?, Some("value9".to_owned()));
+    }
+    assert_eq!(backup.get("key0".to_owned())?, None);
+
+    let backup_size = std::fs::metadata(backup_dir.path().join("backup").join("store"))?.len();
+    assert!(backup_size < source_before.len() as u64);
+    assert_eq!(std::fs::read(&source_log)?, source_before);
+
+    Ok(())
+}
+
+// Opening a regular file instead of a directory should fail with a clear error.
+#[test]
+fn open_file_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_path = temp_dir.path().join("somefile");
+    std::fs::write(&file_path, b"not a store")?;
+
+    match KvStore::open(&file_path) {
+        Err(KvError::NotADirectoryError(p)) => assert_eq!(p, file_path),
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(_) => panic!("open on a file should fail"),
+    }
+
+    Ok(())
+}
+
+// `needs_compaction` should only report true once the log passes the threshold.
+#[test]
+fn needs_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.needs_compaction());
+    drop(store);
+
+    // inflate the log directly so no write has had a chance to compact it
+    let record = "{\"Set\":[\"key\",\"value\"]}\n";
+    let content = record.repeat(1024 * 1024 / record.len() + 1);
+    std::fs::write(temp_dir.path().join("store"), content)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.needs_compaction());
+
+    // the next write compacts the log
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(!store.needs_compaction());
+
+    Ok(())
+}
+
+// Compressed values should take less space on disk and read back unchanged,
+// alongside uncompressed records written earlier.
+#[test]
+fn compressed_values() -> Result<()> {
+    for compression in [Compression::Gzip, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("plain".to_owned(), "value".to_owned())?;
+        drop(store);
+
+        let value = "compressible ".repeat(10_000);
+        let mut store = KvStore::builder()
+            .compression(compression)
+            .open(temp_dir.path())?;
+        store.set("big".to_owned(), value.clone())?;
+
+        let log_size = std::fs::metadata(temp_dir.path().join("store"))?.len();
+        assert!(log_size < value.len() as u64);
+        assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+        assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+
+        // Open from disk again without compression and check both records.
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("big".to_owned())?, Some(value));
+        assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// `changes_since` should return the tail of the log with record offsets.
+#[test]
+fn changes_since_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let all = store.changes_since(0)?;
+    assert_eq!(all.len(), 2);
+    assert_eq!(all[0].0, 0);
+
+    let consumed = std::fs::metadata(temp_dir.path().join("store"))?.len();
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let tail = store.changes_since(consumed)?;
+    assert_eq!(tail.len(), 2);
+    assert_eq!(tail[0].0, consumed);
+    assert!(matches!(&tail[0].1, Op::SetWithMeta { key, value, .. } if key == "key3" && value == "value3"));
+    assert!(matches!(&tail[1].1, Op::Rm(k) if k == "key1"));
+
+    // an offset in the middle of a record is rejected
+    assert!(matches!(
+        store.changes_since(consumed + 1),
+        Err(KvError::OffsetGoneError(_))
+    ));
+
+    Ok(())
+}
+
+// Offsets taken before a compaction should be reported as gone.
+#[test]
+fn changes_since_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let log_len = || std::fs::metadata(temp_dir.path().join("store")).unwrap().len();
+    let value = "v".repeat(1024);
+    for _ in 0..5 {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+    let consumed = log_len();
+
+    // write until the log shrinks, i.e. a compaction ran
+    let mut prev_len = consumed;
+    loop {
+        store.set("key1".to_owned(), value.clone())?;
+        if log_len() < prev_len {
+            break;
+        }
+        prev_len = log_len();
+    }
+
+    assert!(matches!(
+        store.changes_since(consumed),
+        Err(KvError::OffsetGoneError(o)) if o == consumed
+    ));
+    let far = 2 * 1024 * 1024;
+    assert!(matches!(store.changes_since(far), Err(KvError::OffsetGoneError(_))));
+    assert!(!store.changes_since(0)?.is_empty());
+
+    Ok(())
+}
+
+// Change positions should never repeat across compactions, so an offset
+// from before one stays gone after new writes and a reopen.
+#[test]
+fn changes_since_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i % 4), format!("value{i}"))?;
+    }
+    let mid = store.changes_since(0)?[10].0;
+    let consumed = store.changes_end();
+    store.compact()?;
+    store.set("key4".to_owned(), "value20".to_owned())?;
+    store.set("key5".to_owned(), "value21".to_owned())?;
+    // a record boundary in the new log that was also a position before
+    let boundary = store.offset_of("key5").unwrap();
+    assert!(boundary < consumed);
+    let end = store.changes_end();
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for offset in [mid, consumed, boundary] {
+        assert!(matches!(store.changes_since(offset), Err(KvError::OffsetGoneError(o)) if o == offset));
+    }
+    assert_eq!(store.changes_end(), end);
+    assert!(store.changes_since(end)?.is_empty());
+
+    // a replica resyncing from 0 gets positions past the old log
+    let all = store.changes_since(0)?;
+    assert_eq!(all.len(), 6);
+    assert!(all[0].0 >= consumed);
+    let (last, _) = all.last().unwrap();
+    assert!(matches!(&store.changes_since(*last)?[..], [(o, Op::SetWithMeta { key, .. })] if o == last && key == "key5"));
+
+    store.set("key6".to_owned(), "value22".to_owned())?;
+    assert!(matches!(&store.changes_since(end)?[..], [(o, Op::SetWithMeta { key, .. })] if *o == end && key == "key6"));
+
+    Ok(())
+}
+
+// Only one handle may have a store open; `close` should release it.
+#[test]
+fn close_releases_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::LockedError)));
+
+    store.close()?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Errors hit while closing should be returned rather than swallowed.
+#[test]
+fn close_reports_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    std::fs::remove_file(temp_dir.path().join("store"))?;
+    assert!(matches!(store.close(), Err(KvError::IoError(_))));
+
+    Ok(())
+}
+
+// After a compaction every live key should still resolve without reopening.
+#[test]
+fn get_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let log_len = || std::fs::metadata(temp_dir.path().join("store")).unwrap().len();
+    let mut prev_len = 0;
+    for iter in 0.. {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}-{}", key_id, iter))?;
+        }
+        if log_len() < prev_len {
+            // compaction triggered; check every key through the fresh index
+            for key_id in 0..100 {
+                let key = format!("key{}", key_id);
+                assert_eq!(store.get(key)?, Some(format!("{}-{}", key_id, iter)));
+            }
+            return Ok(());
+        }
+        prev_len = log_len();
+    }
+
+    unreachable!()
+}
+
+// The workload generator should produce the same ops for the same seed.
+#[cfg(feature = "bench")]
+#[test]
+fn random_workload_deterministic() {
+    let ops = kvs::random_workload(500, 7);
+    assert_eq!(ops.len(), 500);
+    assert_eq!(ops, kvs::random_workload(500, 7));
+    assert_ne!(ops, kvs::random_workload(500, 8));
+}
+
+// A read-only open should work on a directory without write permission.
+#[cfg(unix)]
+#[test]
+fn open_read_only_dir() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("store.lock"))?;
+
+    let permissions = |mode| std::fs::Permissions::from_mode(mode);
+    std::fs::set_permissions(temp_dir.path(), permissions(0o555))?;
+
+    let result = (|| {
+        let mut store = KvStore::open_read_only(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert!(matches!(
+            store.set("key2".to_owned(), "value2".to_owned()),
+            Err(KvError::ReadOnlyError)
+        ));
+        assert!(!temp_dir.path().join("store.lock").exists());
+        store.close()
+    })();
+
+    std::fs::set_permissions(temp_dir.path(), permissions(0o755))?;
+    result
+}
+
+// The index memory estimate should scale with key count and key length.
+#[test]
+fn index_memory_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.index_memory_estimate(), 0);
+
+    for key_id in 0..100 {
+        store.set(format!("key{:05}", key_id), "value".to_owned())?;
+    }
+    let short_keys = store.index_memory_estimate();
+
+    for key_id in 100..200 {
+        store.set(format!("key{:05}", key_id), "value".to_owned())?;
+    }
+    let doubled = store.index_memory_estimate();
+    assert_eq!(doubled, 2 * short_keys);
+
+    let long_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut long_store = KvStore::open(long_dir.path())?;
+    for key_id in 0..100 {
+        long_store.set(format!("{}{:05}", "k".repeat(100), key_id), "value".to_owned())?;
+    }
+    assert!(long_store.index_memory_estimate() > short_keys + 100 * 90);
+
+    Ok(())
+}
+
+// `rename` should move a value to a new key, overwriting any existing value.
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert!(store.rename("key1", "key3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+    // onto an existing key
+    assert!(store.rename("key3", "key2".to_owned())?);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Renaming a missing key should report false and write nothing.
+#[test]
+fn rename_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_len = std::fs::metadata(temp_dir.path().join("store"))?.len();
+
+    assert!(!store.rename("missing", "key2".to_owned())?);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(std::fs::metadata(temp_dir.path().join("store"))?.len(), log_len);
+
+    Ok(())
+}
+
+// With `strict_remove(false)` removing a missing key should succeed.
+#[test]
+fn remove_non_existent_key_lenient() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().strict_remove(true).open(temp_dir.path())?;
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvError::KeyNotFoundError)));
+    drop(store);
+
+    let mut store = KvStore::builder().strict_remove(false).open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(std::fs::metadata(temp_dir.path().join("store"))?.len(), 0);
+
+    Ok(())
+}
+
+// `compact_from_scan` should repair a store whose index no longer matches
+// the log.
+#[test]
+fn compact_from_scan_stale_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    // shift every record behind the open store's back so that all of its
+    // index entries now point at the wrong offsets
+    let log = temp_dir.path().join("store");
+    let content = std::fs::read_to_string(&log)?;
+    std::fs::write(&log, format!("{{\"Set\":[\"key0\",\"value0\"]}}\n{}", content))?;
+
+    store.compact_from_scan()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(std::fs::read_to_string(&log)?.lines().count(), 3);
+
+    Ok(())
+}
+
+// A compaction should log its before and after sizes.
+#[test]
+fn compaction_logs_sizes() -> Result<()> {
+    testing_logger::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let before = std::fs::metadata(temp_dir.path().join("store"))?.len();
+    store.compact_from_scan()?;
+    let after = std::fs::metadata(temp_dir.path().join("store"))?.len();
+
+    testing_logger::validate(|logs| {
+        let expected = format!("from {} to {} bytes", before, after);
+        assert!(logs
+            .iter()
+            .any(|l| l.level == log::Level::Info && l.body.contains(&expected)));
+    });
+
+    Ok(())
+}
+
+// `remove_batch` should report which keys existed and remove only those.
+#[test]
+fn remove_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let keys = ["key1", "missing", "key3", "key1"].map(str::to_owned);
+    assert_eq!(store.remove_batch(&keys)?, vec![true, false, true, false]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Stores with different file prefixes should share a directory independently.
+#[test]
+fn file_prefix_families() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut first = KvStore::builder().file_prefix("first").open(temp_dir.path())?;
+    let mut second = KvStore::builder().file_prefix("second").open(temp_dir.path())?;
+
+    for iter in 0..5 {
+        first.set("key".to_owned(), format!("first{}", iter))?;
+        second.set("key".to_owned(), format!("second{}", iter))?;
+    }
+    second.set("only_second".to_owned(), "value".to_owned())?;
+    assert!(temp_dir.path().join("first-store").exists());
+    assert!(temp_dir.path().join("second-store").exists());
+    assert!(!temp_dir.path().join("store").exists());
+
+    first.compact_from_scan()?;
+    assert_eq!(first.get("key".to_owned())?, Some("first4".to_owned()));
+    assert_eq!(first.get("only_second".to_owned())?, None);
+    assert_eq!(second.get("key".to_owned())?, Some("second4".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(first);
+    drop(second);
+    let first = KvStore::builder().file_prefix("first").open(temp_dir.path())?;
+    let second = KvStore::builder().file_prefix("second").open(temp_dir.path())?;
+    assert_eq!(first.get("key".to_owned())?, Some("first4".to_owned()));
+    assert_eq!(second.get("key".to_owned())?, Some("second4".to_owned()));
+    assert_eq!(second.get("only_second".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// `reload_index` should pick up records appended outside this handle.
+#[test]
+fn reload_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("store"))?;
+    writeln!(log, "{{\"Set\":[\"key2\",\"value2\"]}}")?;
+    writeln!(log, "{{\"Rm\":\"key1\"}}")?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.reload_index()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // the reloaded log size is used for the next write
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `index_entries` should list live keys in order with their offsets.
+#[test]
+fn index_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let entries: Vec<(String, u64)> = store
+        .index_entries()
+        .map(|(k, offset)| (k.clone(), offset))
+        .collect();
+    let keys: Vec<&str> = entries.iter().map(|(k, _)| k.as_str()).collect();
+    assert_eq!(keys, vec!["key1", "key3"]);
+    for (key, offset) in &entries {
+        assert_eq!(store.offset_of(key), Some(*offset));
+    }
+    assert_eq!(store.offset_of("key2"), None);
+    assert_eq!(store.offset_of("key3"), Some(0));
+
+    Ok(())
+}
+
+// With `max_total_bytes` the log should stay under the cap by dropping the
+// least recently written keys.
+#[test]
+fn max_total_bytes_evicts_oldest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let max = 4096;
+    let mut store = KvStore::builder().max_total_bytes(Some(max)).open(temp_dir.path())?;
+
+    for key_id in 0..200 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+        // overwrites should not count as extra live data
+        store.set("hot".to_owned(), format!("hot{}", key_id))?;
+        assert!(std::fs::metadata(temp_dir.path().join("store"))?.len() <= max);
+    }
+
+    assert_eq!(store.get("key000".to_owned())?, None);
+    assert_eq!(store.get("key199".to_owned())?, Some("value199".to_owned()));
+    assert_eq!(store.get("hot".to_owned())?, Some("hot199".to_owned()));
+
+    // Open from disk again and check that the survivors are a recent suffix.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let survivors: Vec<usize> = (0..200)
+        .filter(|key_id| store.offset_of(&format!("key{:03}", key_id)).is_some())
+        .collect();
+    assert!(survivors.len() > 10);
+    assert_eq!(survivors, (200 - survivors.len()..200).collect::<Vec<_>>());
+    for key_id in survivors {
+        assert_eq!(store.get(format!("key{:03}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    drop(store);
+
+    // write order should survive a compaction, even when it is not key order
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().max_total_bytes(Some(600)).open(temp_dir.path())?;
+    let keys = ["z", "a", "m", "n", "o", "y", "b"];
+    store.set(keys[0].to_owned(), "value".repeat(10))?;
+    store.set(keys[1].to_owned(), "value".repeat(10))?;
+    store.compact()?;
+    for key in &keys[2..] {
+        store.set(key.to_string(), "value".repeat(10))?;
+    }
+    let survivors: Vec<&str> = keys.iter().copied().filter(|key| store.offset_of(key).is_some()).collect();
+    assert!(survivors.len() < keys.len());
+    assert_eq!(survivors, keys[keys.len() - survivors.len()..]);
+
+    Ok(())
+}
+
+// A write larger than `max_total_bytes` on its own should fail, leaving the
+// store as it was, instead of being evicted right away.
+#[test]
+fn max_total_bytes_rejects_oversized_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().max_total_bytes(Some(200)).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_size = store.log_size();
+
+    assert!(matches!(
+        store.set("key2".to_owned(), "v".repeat(400)),
+        Err(KvError::RecordTooLargeError { max: 200, .. })
+    ));
+    let mut writer = store.begin_value("key3".to_owned())?;
+    writer.write_all("v".repeat(400).as_bytes())?;
+    assert!(matches!(writer.commit(), Err(KvError::RecordTooLargeError { max: 200, .. })));
+
+    assert_eq!(store.log_size(), log_size);
+    assert_eq!(std::fs::metadata(temp_dir.path().join("store"))?.len(), log_size);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// `set_if_absent` should only write keys that are not present.
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let log_len = std::fs::metadata(temp_dir.path().join("store"))?.len();
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(std::fs::metadata(temp_dir.path().join("store"))?.len(), log_len);
+
+    Ok(())
+}
+
+// A record with invalid UTF-8 should be reported with its offset.
+#[test]
+fn non_utf8_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = b"{\"Set\":[\"key1\",\"value1\"]}\n".to_vec();
+    let mut content = first.clone();
+    content.extend_from_slice(b"{\"Set\":[\"key2\",\"\xff\xfe\"]}\n");
+    std::fs::write(temp_dir.path().join("store"), content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::NonUtf8RecordError { offset }) => assert_eq!(offset, first.len() as u64),
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(_) => panic!("open should fail on a non UTF-8 record"),
+    }
+
+    Ok(())
+}
+
+// With a flush interval, writes should reach the file from the background
+// thread without any explicit flush.
+#[test]
+fn flush_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .flush_interval(Some(Duration::from_millis(300)))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(std::fs::metadata(temp_dir.path().join("store"))?.len(), 0);
+    // this handle sees its own buffered writes
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    thread::sleep(Duration::from_millis(600));
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(reader);
+
+    // buffered writes are flushed on drop
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `export_csv` should quote awkward values so a CSV reader recovers them.
+#[test]
+fn export_csv() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "has, comma".to_owned())?;
+    store.set("a".to_owned(), "has \"quotes\"".to_owned())?;
+    store.set("c".to_owned(), "multi\nline".to_owned())?;
+    store.set("d".to_owned(), "removed".to_owned())?;
+    store.remove("d".to_owned())?;
+
+    let mut out = Vec::new();
+    store.export_csv(&mut out)?;
+
+    let mut reader = csv::Reader::from_reader(out.as_slice());
+    assert_eq!(reader.headers().unwrap(), vec!["key", "value"]);
+    let rows: Vec<(String, String)> = reader
+        .deserialize()
+        .collect::<std::result::Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            ("a".to_owned(), "has \"quotes\"".to_owned()),
+            ("b".to_owned(), "has, comma".to_owned()),
+            ("c".to_owned(), "multi\nline".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+// `skip_redundant_writes` should avoid appending a value identical to the
+// current one.
+#[test]
+fn skip_redundant_writes() -> Result<()> {
+    for skip in [true, false] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder().skip_redundant_writes(skip).open(temp_dir.path())?;
+        let log_len = || std::fs::metadata(temp_dir.path().join("store")).unwrap().len();
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let first = log_len();
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(log_len() == first, skip);
+
+        // a different value is always written
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert!(log_len() > first);
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// `iter` should yield a consistent snapshot even if the log is compacted
+// part way through.
+#[test]
+fn iter_snapshot_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id * 2))?;
+    }
+
+    let mut iter = store.iter()?;
+    let mut seen: Vec<(String, String)> = iter.by_ref().take(10).collect::<Result<_>>()?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), "changed".to_owned())?;
+    }
+    store.remove("key050".to_owned())?;
+    store.compact_from_scan()?;
+
+    for pair in iter {
+        seen.push(pair?);
+    }
+    let expected: Vec<(String, String)> = (0..100)
+        .map(|key_id| (format!("key{:03}", key_id), format!("value{}", key_id * 2)))
+        .collect();
+    assert_eq!(seen, expected);
+
+    let after: Vec<(String, String)> = store.iter()?.collect::<Result<_>>()?;
+    assert_eq!(after.len(), 99);
+    assert!(after.iter().all(|(_, v)| v == "changed"));
+
+    Ok(())
+}
+
+// The store should keep working after `shrink_index` following mass removal.
+#[test]
+fn shrink_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let keys: Vec<String> = (0..1000).filter(|i| i % 10 != 0).map(|i| format!("key{}", i)).collect();
+    store.remove_batch(&keys)?;
+    let before = store.index_memory_estimate();
+
+    store.shrink_index();
+    assert_eq!(store.index_memory_estimate(), before);
+    assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
+    assert_eq!(store.get("key11".to_owned())?, None);
+    store.set("key11".to_owned(), "again".to_owned())?;
+    assert_eq!(store.get("key11".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.index_entries().count(), 101);
+
+    Ok(())
+}
+
+// `get_version` should return older values until they are compacted away.
+#[test]
+fn get_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "other".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(store.get_version("key1", 0)?, Some("value3".to_owned()));
+    assert_eq!(store.get_version("key1", 1)?, Some("value2".to_owned()));
+    assert_eq!(store.get_version("key1", 2)?, Some("value1".to_owned()));
+    assert_eq!(store.get_version("key1", 3)?, None);
+    assert_eq!(store.get_version("key1", usize::MAX)?, None);
+    assert_eq!(store.get_version("missing", 0)?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.compact_from_scan()?;
+    assert_eq!(store.get_version("key1", 0)?, Some("value3".to_owned()));
+    assert_eq!(store.get_version("key1", 1)?, None);
+
+    Ok(())
+}
+
+// The store should work normally with I/O retries enabled.
+#[test]
+fn io_retries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().io_retries(3).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `remove_prefix` should remove exactly the keys sharing the prefix.
+#[test]
+fn remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["user", "user:1", "user:2", "userx", "use", "user;"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+
+    assert_eq!(store.remove_prefix("user:")?, 2);
+    assert_eq!(store.get("user:1".to_owned())?, None);
+    assert_eq!(store.get("user:2".to_owned())?, None);
+    for key in ["user", "userx", "use", "user;"] {
+        assert_eq!(store.get(key.to_owned())?, Some("value".to_owned()));
+    }
+    assert_eq!(store.remove_prefix("user:")?, 0);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("user:1".to_owned())?, None);
+    assert_eq!(store.get("userx".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Opening an existing zero-byte log should yield an empty, writable store.
+#[test]
+fn open_zero_byte_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::File::create(temp_dir.path().join("store"))?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 0);
+    assert!(store.is_empty());
+    assert_eq!(store.log_size(), 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Truncating the log externally and reloading should also be clean.
+    store.close()?;
+    std::fs::OpenOptions::new().write(true).open(temp_dir.path().join("store"))?.set_len(0)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.log_size(), 0);
+
+    Ok(())
+}
+
+// `snapshot_get` on a shared store should never see a multi-key update
+// half-applied.
+#[test]
+fn snapshot_get_consistent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?);
+    let keys = vec!["a".to_owned(), "b".to_owned()];
+    store.update(|store| {
+        store.set("a".to_owned(), "0".to_owned())?;
+        store.set("b".to_owned(), "0".to_owned())
+    })?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..200 {
+                store.update(|store| {
+                    store.set("a".to_owned(), i.to_string())?;
+                    store.set("b".to_owned(), i.to_string())
+                })?;
+            }
+            Ok(())
+        })
+    };
+
+    for _ in 0..200 {
+        let values = store.snapshot_get(&keys)?;
+        assert_eq!(values[0], values[1]);
+    }
+    writer.join().unwrap()?;
+
+    assert_eq!(store.snapshot_get(&keys)?, vec![Some("199".to_owned()), Some("199".to_owned())]);
+    assert_eq!(store.snapshot_get(&["c".to_owned()])?, vec![None]);
+
+    Ok(())
+}
+
+// Stores with the same live data should have the same digest, however the
+// data got there.
+#[test]
+fn digest() -> Result<()> {
+    let dir1 = TempDir::new().expect("unable to create temporary working directory");
+    let dir2 = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store1 = KvStore::open(dir1.path())?;
+    store1.set("key1".to_owned(), "value1".to_owned())?;
+    store1.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut store2 = KvStore::open(dir2.path())?;
+    store2.set("key2".to_owned(), "old".to_owned())?;
+    store2.set("key3".to_owned(), "value3".to_owned())?;
+    store2.set("key1".to_owned(), "value1".to_owned())?;
+    store2.remove("key3".to_owned())?;
+    store2.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store1.digest()?, store2.digest()?);
+    store2.compact_from_scan()?;
+    assert_eq!(store1.digest()?, store2.digest()?);
+
+    store2.set("key2".to_owned(), "changed".to_owned())?;
+    assert_ne!(store1.digest()?, store2.digest()?);
+
+    Ok(())
+}
+
+// The log should be created, and replaced by compaction, with the
+// configured permissions.
+#[cfg(unix)]
+#[test]
+fn file_mode() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mode = || std::fs::metadata(temp_dir.path().join("store")).unwrap().permissions().mode() & 0o777;
+
+    let mut store = KvStore::builder().file_mode(0o600).open(temp_dir.path())?;
+    assert_eq!(mode(), 0o600);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact_from_scan()?;
+    assert_eq!(mode(), 0o600);
+
+    Ok(())
+}
+
+// Applying a store's `changes_since` to a fresh store should reproduce its
+// live data.
+#[test]
+fn apply_raw_replicates() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut leader = KvStore::open(leader_dir.path())?;
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.set("key1".to_owned(), "value3".to_owned())?;
+    leader.remove("key2".to_owned())?;
+    leader.set("key3".to_owned(), "value4".to_owned())?;
+
+    let mut follower = KvStore::open(follower_dir.path())?;
+    follower.apply_raw(&leader.changes_since(0)?)?;
+    assert_eq!(follower.digest()?, leader.digest()?);
+    assert_eq!(follower.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(follower.get("key2".to_owned())?, None);
+
+    assert!(matches!(
+        follower.apply_raw(&[(0, Op::Get("key1".to_owned()))]),
+        Err(KvError::InvalidCommandError)
+    ));
+
+    Ok(())
+}
+
+// `kvs export` followed by `kvs import` in another directory should restore
+// every pair.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dump = source_dir.path().join("dump.json");
+
+    let mut store = KvStore::open(source_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "with \"quotes\",\nand lines".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--path"])
+        .arg(&dump)
+        .current_dir(&source_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("import")
+        .arg(&dump)
+        .current_dir(&dest_dir)
+        .assert()
+        .success();
+
+    let store = KvStore::open(dest_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("with \"quotes\",\nand lines".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.len(), 2);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "missing.json"])
+        .current_dir(&dest_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+// `last_modified` should report the write time of the latest record, and
+// `None` for records written before timestamps were recorded.
+#[test]
+fn last_modified() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("store"), "{\"Set\":[\"old\",\"value\"]}\n")?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let modified = store.last_modified("key1")?.expect("timestamp recorded");
+    let age = std::time::SystemTime::now().duration_since(modified).unwrap_or_default();
+    assert!(age < Duration::from_secs(1));
+
+    assert_eq!(store.get("old".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.last_modified("old")?, None);
+    assert_eq!(store.last_modified("missing")?, None);
+
+    Ok(())
+}
+
+// `status` should tell present, removed and never-set keys apart until
+// compaction drops the tombstones.
+#[test]
+fn key_status() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    assert_eq!(store.status("key1")?, KeyStatus::Present("value1".to_owned()));
+    assert_eq!(store.status("key2")?, KeyStatus::Deleted);
+    assert_eq!(store.status("key3")?, KeyStatus::NeverExisted);
+
+    // a key set again after its removal is present
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.status("key2")?, KeyStatus::Present("value3".to_owned()));
+    store.remove("key2".to_owned())?;
+
+    store.compact_from_scan()?;
+    assert_eq!(store.status("key2")?, KeyStatus::NeverExisted);
+
+    Ok(())
+}
+
+// Stores opened through a `StoreManager` should be isolated from each other
+// and cached by name.
+#[test]
+fn store_manager() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut manager = StoreManager::new(temp_dir.path());
+
+    manager.get_store("users")?.set("key1".to_owned(), "alice".to_owned())?;
+    manager.get_store("orders")?.set("key1".to_owned(), "order1".to_owned())?;
+    assert_eq!(manager.get_store("users")?.get("key1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(manager.get_store("orders")?.get("key1".to_owned())?, Some("order1".to_owned()));
+
+    let first: *const KvStore = manager.get_store("users")?;
+    let second: *const KvStore = manager.get_store("users")?;
+    assert_eq!(first, second);
+
+    // the manager holds each store's lock
+    assert!(matches!(KvStore::open(temp_dir.path().join("users")), Err(KvError::LockedError)));
+
+    for name in ["", "..", "a/b"] {
+        assert!(matches!(manager.get_store(name), Err(KvError::InvalidStoreNameError(_))));
+    }
+
+    Ok(())
+}
+
+// A preallocated log should keep its reserved size while the logical size
+// tracks the records, across reopening and compaction.
+#[test]
+fn preallocate_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_len = || std::fs::metadata(temp_dir.path().join("store")).unwrap().len();
+    let builder = KvStore::builder().preallocate_bytes(64 * 1024);
+
+    let mut store = builder.clone().open(temp_dir.path())?;
+    assert_eq!(file_len(), 64 * 1024);
+    assert_eq!(store.log_size(), 0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let log_size = store.log_size();
+    assert!(log_size > 0 && log_size < 1024);
+    assert_eq!(file_len(), 64 * 1024);
+    drop(store);
+
+    // replay stops at the padding and new writes land after the records
+    let mut store = builder.open(temp_dir.path())?;
+    assert_eq!(store.log_size(), log_size);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    store.compact_from_scan()?;
+    assert_eq!(file_len(), 64 * 1024);
+    assert!(store.log_size() < log_size * 2);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    // a handle without the option still writes over the padding
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// `get_with_offset` should report the offset of the record it read, which
+// `read_op_at` can read again.
+#[test]
+fn get_with_offset() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+
+    let (value, offset) = store.get_with_offset("key1")?.expect("key1 is set");
+    assert_eq!(value, "value3");
+    assert_eq!(Some(offset), store.offset_of("key1"));
+    assert!(matches!(store.read_op_at(offset)?, Op::SetWithMeta { key, value, .. } if key == "key1" && value == "value3"));
+
+    assert_eq!(store.get_with_offset("key3")?, None);
+    assert!(matches!(store.read_op_at(store.log_size()), Err(KvError::OffsetGoneError(_))));
+
+    Ok(())
+}
+
+// `wait_for` should return a value set by another thread while waiting, or
+// `None` once the timeout passes.
+#[test]
+fn wait_for() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?);
+
+    let setter = {
+        let store = store.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            store.set("other".to_owned(), "ignored".to_owned())?;
+            thread::sleep(Duration::from_millis(50));
+            store.set("key1".to_owned(), "value1".to_owned())
+        })
+    };
+    assert_eq!(store.wait_for("key1", Duration::from_secs(10))?, Some("value1".to_owned()));
+    setter.join().unwrap()?;
+
+    // present keys return immediately
+    assert_eq!(store.wait_for("key1", Duration::ZERO)?, Some("value1".to_owned()));
+    assert_eq!(store.wait_for("key2", Duration::from_millis(50))?, None);
+
+    Ok(())
+}
+
+// `log_bounds` should span the whole log file.
+#[test]
+fn log_bounds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .flush_interval(Some(Duration::from_secs(3600)))
+        .open(temp_dir.path())?;
+    assert_eq!(store.log_bounds()?, (0, 0));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let (start, end) = store.log_bounds()?;
+    assert_eq!(start, 0);
+    assert_eq!(end, std::fs::metadata(temp_dir.path().join("store"))?.len());
+    assert_eq!(store.changes_since(start)?.len(), 3);
+
+    Ok(())
+}
+
+// Compacting the same live records twice should produce byte-identical logs.
+#[test]
+fn compaction_deterministic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let copy1 = TempDir::new().expect("unable to create temporary working directory");
+    let copy2 = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::builder().compression(Compression::Gzip).open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i % 20), format!("value{i}"))?;
+    }
+    store.remove("key3".to_owned())?;
+
+    drop(store.compact_into(copy1.path())?);
+    drop(store.compact_into(copy2.path())?);
+    let log1 = std::fs::read(copy1.path().join("store"))?;
+    assert_eq!(log1, std::fs::read(copy2.path().join("store"))?);
+
+    // compacting in place writes the same bytes as well
+    store.compact_from_scan()?;
+    drop(store);
+    assert_eq!(log1, std::fs::read(temp_dir.path().join("store"))?);
+
+    Ok(())
+}
+
+// `kvs get --quiet <KEY>` should print nothing on stdout for a non-existent
+// key and exit with code 2.
+#[test]
+fn cli_get_non_existent_key_quiet() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for args in [["get", "--quiet", "key1"], ["--quiet", "get", "key1"]] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+            .code(2)
+            .stdout(is_empty())
+            .stderr(eq("Key not found").trim());
+    }
+}
+
+// `raw_records` should yield superseded sets and tombstones in log order.
+#[test]
+fn raw_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let records = store.raw_records()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].0, 0);
+    assert!(matches!(&records[0].1, Op::SetWithMeta { key, value, .. } if key == "key1" && value == "value1"));
+    assert!(matches!(&records[1].1, Op::SetWithMeta { key, value, .. } if key == "key1" && value == "value2"));
+    assert!(matches!(&records[2].1, Op::Rm(key) if key == "key1"));
+    assert!(records[0].0 < records[1].0 && records[1].0 < records[2].0);
+
+    Ok(())
+}
+
+// With a cooldown, compactions should be bounded by elapsed time rather than
+// by the number of writes.
+#[test]
+fn compaction_cooldown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cooldown = Duration::from_millis(50);
+    let mut store = KvStore::builder()
+        .compaction_threshold(1)
+        .compaction_cooldown(Some(cooldown))
+        .open(temp_dir.path())?;
+
+    let start = std::time::Instant::now();
+    let mut writes = 0;
+    while start.elapsed() < Duration::from_millis(300) {
+        store.set(format!("key{}", writes % 10), format!("value{writes}"))?;
+        writes += 1;
+    }
+    let elapsed = start.elapsed();
+
+    let bound = (elapsed.as_millis() / cooldown.as_millis()) as u64 + 1;
+    assert!(store.compaction_count() >= 1);
+    assert!(store.compaction_count() <= bound, "{} compactions in {elapsed:?}", store.compaction_count());
+    assert!(writes > store.compaction_count());
+    assert!(store.get("key0".to_owned())?.is_some());
+
+    Ok(())
+}
+
+// `get` should work through a shared reference from several threads.
+#[test]
+fn get_through_arc() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+
+    let store = std::sync::Arc::new(store);
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..10 {
+                    assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// `export_filtered` should only write the pairs whose key matches.
+#[test]
+fn export_filtered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("keep:1".to_owned(), "value1".to_owned())?;
+    store.set("drop:1".to_owned(), "value2".to_owned())?;
+    store.set("keep:2".to_owned(), "value3".to_owned())?;
+
+    let mut out = Vec::new();
+    assert_eq!(store.export_filtered(&mut out, |key| key.starts_with("keep:"))?, 2);
+    assert_eq!(String::from_utf8(out).unwrap(), r#"{"keep:1":"value1","keep:2":"value3"}"#);
+
+    let mut out = Vec::new();
+    assert_eq!(store.export_filtered(&mut out, |_| false)?, 0);
+    assert_eq!(out, b"{}");
+
+    Ok(())
+}
+
+// `repair` should drop corrupt records in the middle of the log and keep
+// every key that still has a valid record.
+#[test]
+fn repair_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.close()?;
+
+    let mut log = std::fs::OpenOptions::new().append(true).open(temp_dir.path().join("store"))?;
+    log.write_all(b"{\"Set\":[\"key1\",\"trunc\n")?;
+    log.write_all(b"\xff\xfe\n")?;
+    log.write_all(b"{\"Set\":[\"key3\",\"value3\"]}\n{\"Rm\":\"key2\"}\n")?;
+    drop(log);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    assert_eq!(KvStore::repair(temp_dir.path())?, RepairReport { dropped: 2, live_keys: 2 });
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // an open store is locked against repair
+    assert!(matches!(KvStore::repair(temp_dir.path()), Err(KvError::LockedError)));
+
+    Ok(())
+}
+
+// A sharded store should route keys with its router and merge reads across
+// shards.
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs = [temp_dir.path().join("shard0"), temp_dir.path().join("shard1")];
+    // keys starting with "a" go to the first shard, all others to the second
+    let router = |key: &str| if key.starts_with('a') { 0 } else { 1 };
+
+    let mut store = ShardedKvStore::open(dirs.clone(), router)?;
+    for key in ["apple", "avocado", "banana", "cherry"] {
+        store.set(key.to_owned(), format!("{key} value"))?;
+    }
+    store.remove("cherry".to_owned())?;
+
+    assert_eq!(store.shard_of("apple"), 0);
+    assert_eq!(store.shard_of("banana"), 1);
+    assert_eq!(store.shard(0).unwrap().get("apple".to_owned())?, Some("apple value".to_owned()));
+    assert_eq!(store.shard(1).unwrap().get("apple".to_owned())?, None);
+    assert_eq!(store.shard(1).unwrap().get("banana".to_owned())?, Some("banana value".to_owned()));
+
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.keys(), vec!["apple", "avocado", "banana"]);
+    drop(store);
+
+    let store = ShardedKvStore::open(dirs, router)?;
+    for key in ["apple", "avocado", "banana"] {
+        assert_eq!(store.get(key.to_owned())?, Some(format!("{key} value")));
+    }
+    assert_eq!(store.get("cherry".to_owned())?, None);
+
+    Ok(())
+}
+
+// Stores reaching the same live pairs through different writes should have
+// equal contents.
+#[test]
+fn content_equals_same_state() -> Result<()> {
+    let dir1 = TempDir::new().expect("unable to create temporary working directory");
+    let dir2 = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store1 = KvStore::open(dir1.path())?;
+    store1.set("key1".to_owned(), "value1".to_owned())?;
+    store1.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut store2 = KvStore::builder().compression(Compression::Zstd).open(dir2.path())?;
+    store2.set("key2".to_owned(), "value2".to_owned())?;
+    store2.set("key3".to_owned(), "value3".to_owned())?;
+    store2.set("key1".to_owned(), "old".to_owned())?;
+    store2.set("key1".to_owned(), "value1".to_owned())?;
+    store2.remove("key3".to_owned())?;
+    store2.compact_from_scan()?;
+
+    assert!(store1.content_equals(&store2)?);
+    assert!(store2.content_equals(&store1)?);
+
+    Ok(())
+}
+
+// One extra key should make the contents differ.
+#[test]
+fn content_equals_extra_key() -> Result<()> {
+    let dir1 = TempDir::new().expect("unable to create temporary working directory");
+    let dir2 = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store1 = KvStore::open(dir1.path())?;
+    let mut store2 = KvStore::open(dir2.path())?;
+    for store in [&mut store1, &mut store2] {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    store2.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert!(!store1.content_equals(&store2)?);
+    assert!(!store2.content_equals(&store1)?);
+
+    Ok(())
+}
+
+// A value validator should reject values before anything is written.
+#[test]
+fn value_validator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder()
+        .value_validator(|value| {
+            serde_json::from_str::<serde_json::Value>(value)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+        .open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), r#"{"name": "alice"}"#.to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(r#"{"name": "alice"}"#.to_owned()));
+
+    let log_size = store.log_size();
+    assert!(matches!(
+        store.set("key2".to_owned(), "{not json".to_owned()),
+        Err(KvError::ValidationError(_))
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.log_size(), log_size);
+
+    Ok(())
+}
+
+// `get_range` should honour inclusive, exclusive and unbounded ends.
+#[test]
+fn get_range() -> Result<()> {
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in ["d", "b", "a", "e", "c"] {
+        store.set(key.to_owned(), format!("{key}-value"))?;
+    }
+    let keys = |start, end| -> Result<Vec<String>> {
+        Ok(store.get_range(start, end)?.into_iter().map(|(k, _)| k).collect())
+    };
+
+    assert_eq!(keys(Included("b"), Included("d"))?, vec!["b", "c", "d"]);
+    assert_eq!(keys(Included("b"), Excluded("d"))?, vec!["b", "c"]);
+    assert_eq!(keys(Excluded("b"), Included("d"))?, vec!["c", "d"]);
+    assert_eq!(keys(Excluded("b"), Excluded("d"))?, vec!["c"]);
+    assert_eq!(keys(Unbounded, Included("c"))?, vec!["a", "b", "c"]);
+    assert_eq!(keys(Excluded("c"), Unbounded)?, vec!["d", "e"]);
+    assert_eq!(keys(Included("bb"), Excluded("dd"))?, vec!["c", "d"]);
+    assert!(keys(Excluded("c"), Excluded("c"))?.is_empty());
+    assert!(keys(Included("d"), Included("b"))?.is_empty());
+
+    assert_eq!(
+        store.get_range(Included("a"), Excluded("c"))?,
+        vec![("a".to_owned(), "a-value".to_owned()), ("b".to_owned(), "b-value".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Paging through a store with `list_page` should visit every key once, in
+// order.
+#[test]
+fn list_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in (0..10).rev() {
+        store.set(format!("key{i}"), "value".to_owned())?;
+    }
+
+    let mut all = Vec::new();
+    let mut token: Option<String> = None;
+    let mut pages = 0;
+    loop {
+        let (page, next) = store.list_page(token.as_deref(), 3)?;
+        assert!(page.len() <= 3);
+        all.extend(page);
+        pages += 1;
+        match next {
+            Some(next) => token = Some(next),
+            None => break,
+        }
+    }
+
+    let expected: Vec<String> = (0..10).map(|i| format!("key{i}")).collect();
+    assert_eq!(all, expected);
+    assert_eq!(pages, 4);
+
+    // an exact final page reports exhaustion right away
+    assert_eq!(store.list_page(Some("key6"), 3)?, (vec!["key7".to_owned(), "key8".to_owned(), "key9".to_owned()], None));
+    assert_eq!(store.list_page(Some("key9"), 3)?, (Vec::new(), None));
+
+    // a zero limit could only answer with a page that looks exhausted
+    assert!(matches!(
+        store.list_page(None, 0),
+        Err(KvError::IoError(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+    ));
+
+    Ok(())
+}
+
+// With `keep_versions(2)`, compaction should keep the two most recent values
+// of a key and drop older ones.
+#[test]
+fn keep_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a threshold of one byte compacts after every write
+    let builder = KvStore::builder().keep_versions(2).compaction_threshold(1);
+    let mut store = builder.clone().open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "other".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("gone".to_owned(), "value".to_owned())?;
+    store.remove("gone".to_owned())?;
+    assert!(store.compaction_count() >= 6);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get_version("key1", 1)?, Some("value2".to_owned()));
+    assert_eq!(store.get_version("key1", 2)?, None);
+    assert_eq!(store.get_version("key2", 0)?, Some("other".to_owned()));
+    assert_eq!(store.get_version("gone", 0)?, None);
+    drop(store);
+
+    let store = builder.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get_version("key1", 1)?, Some("value2".to_owned()));
+    assert_eq!(store.len(), 2);
+
+    Ok(())
+}
+
+// An empty value should round-trip and stay distinct from a missing key.
+#[test]
+fn empty_value() -> Result<()> {
+    for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder().compression(compression).open(temp_dir.path())?;
+        store.set("key1".to_owned(), String::new())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(String::new()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+
+        store.compact_from_scan()?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(String::new()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", ""])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--quiet", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("\n"));
+
+    Ok(())
+}
+
+// `get_history` should list values most recent first and stop at the last
+// removal.
+#[test]
+fn get_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.get_history("key1", 10)?.is_empty());
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key2".to_owned(), "other".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.set("key1".to_owned(), "value5".to_owned())?;
+
+    assert_eq!(store.get_history("key1", 10)?, vec!["value5", "value4", "value3"]);
+    assert_eq!(store.get_history("key1", 2)?, vec!["value5", "value4"]);
+    assert!(store.get_history("key1", 0)?.is_empty());
+    assert!(store.get_history("key3", 10)?.is_empty());
+    // get_version still reaches past the removal
+    assert_eq!(store.get_version("key1", 3)?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `kvs get --format json` should print the key with its value, or with
+// `found: false` when it is missing.
+#[test]
+fn cli_get_format_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value \"1\"".to_owned())?;
+    drop(store);
+
+    let get_json = |key: &str| -> serde_json::Value {
+        let output = Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", key, "--format", "json"])
+            .current_dir(&temp_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice(&output.stdout).unwrap()
+    };
+
+    let present = get_json("key1");
+    assert_eq!(present["key"], "key1");
+    assert_eq!(present["value"], "value \"1\"");
+
+    let absent = get_json("key2");
+    assert_eq!(absent["key"], "key2");
+    assert_eq!(absent["found"], false);
+    assert!(absent.get("value").is_none());
+
+    Ok(())
+}
+
+// Reads through a memory map should match regular reads, across appends and
+// compaction.
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().mmap(true).compression(Compression::Gzip).open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i % 200), format!("value{i}"))?;
+        // reading as we go forces the mapping to grow
+        assert_eq!(store.get(format!("key{}", i % 200))?, Some(format!("value{i}")));
+    }
+    store.compact_from_scan()?;
+    store.set("key0".to_owned(), "after compaction".to_owned())?;
+
+    let plain = KvStore::open_read_only(temp_dir.path())?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{i}"))?, plain.get(format!("key{i}"))?);
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("after compaction".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+    drop(store);
+
+    // an abandoned value is cut off the preallocated log in place
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().mmap(true).preallocate_bytes(64 * 1024).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let mut writer = store.begin_value("abandoned".to_owned())?;
+    writer.write_all("x".repeat(128 * 1024).as_bytes())?;
+    drop(writer);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("abandoned".to_owned())?, None);
+
+    Ok(())
+}
+
+// A value streamed in chunks through `begin_value` should read back whole,
+// and an uncommitted one should leave no trace.
+#[test]
+fn begin_value_streams() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // multi-byte characters and quotes split across chunk boundaries
+    let value = "chunk \"é€😀\" \n".repeat(50_000);
+    let mut writer = store.begin_value("big".to_owned())?;
+    for chunk in value.as_bytes().chunks(7) {
+        writer.write_all(chunk)?;
+    }
+    writer.commit()?;
+    assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+
+    let log_size = store.log_size();
+    let mut writer = store.begin_value("abandoned".to_owned())?;
+    writer.write_all(b"never committed")?;
+    drop(writer);
+    assert_eq!(store.log_size(), log_size);
+    assert_eq!(std::fs::metadata(temp_dir.path().join("store"))?.len(), log_size);
+    assert_eq!(store.get("abandoned".to_owned())?, None);
+
+    let mut writer = store.begin_value("bad".to_owned())?;
+    assert!(writer.write_all(b"\xff").is_err());
+    writer.write_all(b"\xe2\x82")?;
+    assert!(matches!(writer.commit(), Err(KvError::CorruptValueError)));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(value));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("bad".to_owned())?, None);
+    assert_eq!(store.len(), 3);
+
+    Ok(())
+}
+
+// A write that fails partway through, here by hitting the file size limit,
+// should leave neither the key nor the partial record behind.
+#[cfg(unix)]
+#[test]
+fn failed_write_rolls_back() -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log_len = || std::fs::metadata(temp_dir.path().join("store")).unwrap().len();
+    let before = log_len();
+
+    let limit = before + 100;
+    let mut cmd = Command::cargo_bin("kvs").unwrap();
+    cmd.args(["set", "big", &"x".repeat(16 * 1024)]).current_dir(&temp_dir);
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            // fail writes past the limit with EFBIG instead of killing the process
+            libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+            let rlimit = libc::rlimit { rlim_cur: limit as libc::rlim_t, rlim_max: limit as libc::rlim_t };
+            if libc::setrlimit(libc::RLIMIT_FSIZE, &rlimit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    cmd.assert().failure();
+    assert_eq!(log_len(), before);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Several read-only handles should coexist, and keep a writable open out
+// while they are open.
+#[test]
+fn concurrent_read_only_opens() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+
+    let reader1 = KvStore::open_read_only(temp_dir.path())?;
+    let reader2 = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader1.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader2.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::LockedError)));
+
+    drop(reader1);
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvError::LockedError)));
+    reader2.close()?;
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    starts: std::sync::Mutex<Vec<usize>>,
+    finishes: std::sync::Mutex<Vec<(u64, u64, Duration)>>,
+}
+
+impl CompactionObserver for RecordingObserver {
+    fn on_start(&self, live_keys: usize) {
+        self.starts.lock().unwrap().push(live_keys);
+    }
+
+    fn on_finish(&self, bytes_before: u64, bytes_after: u64, duration: Duration) {
+        self.finishes.lock().unwrap().push((bytes_before, bytes_after, duration));
+    }
+}
+
+// A compaction observer should see each compaction start and finish with
+// the log sizes around it.
+#[test]
+fn compaction_observer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let observer = std::sync::Arc::new(RecordingObserver::default());
+    let mut store = KvStore::builder()
+        .compaction_observer(observer.clone())
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 3), format!("value{i}"))?;
+    }
+    assert!(observer.starts.lock().unwrap().is_empty());
+
+    let before = store.log_size();
+    store.compact_from_scan()?;
+
+    assert_eq!(*observer.starts.lock().unwrap(), vec![3]);
+    let finishes = observer.finishes.lock().unwrap();
+    assert_eq!(finishes.len(), 1);
+    let (bytes_before, bytes_after, duration) = finishes[0];
+    assert_eq!(bytes_before, before);
+    assert_eq!(bytes_after, store.log_size());
+    assert!(bytes_after < bytes_before);
+    assert!(duration > Duration::ZERO);
+
+    Ok(())
+}
+
+// Overwriting a key should raise write amplification. Compacting should
+// raise it by one more copy of the live records, and reopening should keep
+// counting the logs compaction replaced.
+#[test]
+fn write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.write_amplification(), 1.0);
+
+    for i in 0..50 {
+        store.set("key".to_owned(), format!("value{i}"))?;
+    }
+    let churned = store.write_amplification();
+    assert!(churned > 10.0);
+
+    store.compact()?;
+    assert!((store.write_amplification() - (churned + 1.0)).abs() < 1e-9);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!((store.write_amplification() - (churned + 1.0)).abs() < 1e-9);
+    store.remove("key".to_owned())?;
+    assert_eq!(store.write_amplification(), f64::INFINITY);
+
+    Ok(())
+}
+
+// Opening with a progress callback should report steady progress through
+// the log, ending at its full length.
+#[test]
+fn open_with_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    drop(store);
+
+    let mut calls = Vec::new();
+    let store = KvStore::open_with_progress(temp_dir.path(), |read, total| calls.push((read, total)))?;
+    let file_len = std::fs::metadata(temp_dir.path().join("store"))?.len();
+
+    assert_eq!(calls.len(), 20);
+    assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(calls.last(), Some(&(file_len, file_len)));
+    assert_eq!(store.get("key7".to_owned())?, Some("value7".to_owned()));
+
+    Ok(())
+}
+
+// Closing with `compact_on_close` should leave only the live records in the
+// log.
+#[test]
+fn compact_on_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().compact_on_close(true).open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i % 5), format!("value{i}"))?;
+    }
+    store.remove("key0".to_owned())?;
+    let churned = store.log_size();
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    let file_len = std::fs::metadata(temp_dir.path().join("store"))?.len();
+    assert!(file_len < churned);
+    assert_eq!(file_len, store.log_size());
+    // the churn before the compaction is still counted
+    assert!(store.write_amplification() > 1.0);
+    assert_eq!(store.len(), 4);
+    for i in 196..200 {
+        assert_eq!(store.get(format!("key{}", i % 5))?, Some(format!("value{i}")));
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// A store opened with a key filter should only serve the keys it accepts,
+// and refuse writes.
+#[test]
+fn open_with_key_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+    store.remove("user:2".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_with_key_filter(temp_dir.path(), |key| key.starts_with("user:"))?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("user:1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(store.get("user:2".to_owned())?, None);
+    assert_eq!(store.get("order:1".to_owned())?, None);
+    assert!(matches!(store.set("user:3".to_owned(), "carol".to_owned()), Err(KvError::ReadOnlyError)));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("order:1".to_owned())?, Some("book".to_owned()));
+
+    Ok(())
+}
+
+// An index snapshot should stay as taken while the store moves on.
+#[test]
+fn index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    store.set("a".to_owned(), "2".to_owned())?;
+
+    let snapshot = store.index_snapshot();
+    assert_eq!(snapshot, vec![
+        ("a".to_owned(), store.offset_of("a").unwrap()),
+        ("b".to_owned(), 0),
+    ]);
+
+    store.set("c".to_owned(), "3".to_owned())?;
+    store.set("a".to_owned(), "4".to_owned())?;
+    store.remove("b".to_owned())?;
+
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(snapshot[1], ("b".to_owned(), 0));
+    let live = store.index_snapshot();
+    let keys: Vec<&str> = live.iter().map(|(k, _)| k.as_str()).collect();
+    assert_eq!(keys, vec!["a", "c"]);
+    assert_ne!(live[0].1, snapshot[0].1);
+
+    Ok(())
+}
+
+// `kvs stats --json` should report the live keys and log usage.
+#[test]
+fn cli_stats_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i % 4), format!("value{i}"))?;
+    }
+    let log_size = store.log_size();
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--json"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats["live_keys"], 4);
+    assert_eq!(stats["log_size"], log_size);
+    assert!(stats["dead_bytes"].as_u64().unwrap() > 0);
+    assert_eq!(stats["compaction_count"], 0);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live keys         4"));
+
+    Ok(())
+}
+
+// `open_or_promote` should move a staged store into an empty target, and
+// leave a target that already holds a store alone.
+#[test]
+fn open_or_promote() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let staging = temp_dir.path().join("staging");
+    let target = temp_dir.path().join("target");
+    std::fs::create_dir(&staging)?;
+    let mut store = KvStore::open(&staging)?;
+    store.set("key1".to_owned(), "staged".to_owned())?;
+    store.close()?;
+
+    let mut store = KvStore::open_or_promote(&staging, &target)?;
+    assert!(!staging.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("staged".to_owned()));
+    store.set("key1".to_owned(), "live".to_owned())?;
+    drop(store);
+
+    std::fs::create_dir(&staging)?;
+    let mut store = KvStore::open(&staging)?;
+    store.set("key1".to_owned(), "staged again".to_owned())?;
+    drop(store);
+    let store = KvStore::open_or_promote(&staging, &target)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("live".to_owned()));
+    assert!(staging.exists());
+
+    Ok(())
+}
+
+// `touch` should move a key's modification time forward and keep its value,
+// compressed or not.
+#[test]
+fn touch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().compression(Compression::Gzip).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let set_at = store.last_modified("key1")?.unwrap();
+
+    thread::sleep(Duration::from_millis(20));
+    assert!(store.touch("key1")?);
+    let touched_at = store.last_modified("key1")?.unwrap();
+    assert!(touched_at > set_at);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(!store.touch("key2")?);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_modified("key1")?, Some(touched_at));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// `find` should return the keys of the live values matching the predicate.
+#[test]
+fn find() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("fruit1".to_owned(), "red apple".to_owned())?;
+    store.set("fruit2".to_owned(), "banana".to_owned())?;
+    store.set("fruit3".to_owned(), "green apple".to_owned())?;
+    store.set("fruit4".to_owned(), "apple pie".to_owned())?;
+    store.set("fruit2".to_owned(), "crab apple".to_owned())?;
+    store.remove("fruit4".to_owned())?;
+
+    assert_eq!(store.find(|value| value.contains("apple"))?, vec!["fruit1", "fruit2", "fruit3"]);
+    assert_eq!(store.find(|value| value.contains("banana"))?, Vec::<String>::new());
+
+    Ok(())
+}
+
+// `log_reader` should yield the log's bytes as they are on disk, and not
+// pick up writes made after it was created.
+#[test]
+fn log_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let mut reader = store.log_reader()?;
+    let on_disk = std::fs::read(temp_dir.path().join("store"))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let mut copied = Vec::new();
+    std::io::copy(&mut reader, &mut copied)?;
+    assert_eq!(copied, on_disk);
+
+    Ok(())
+}
+
+// Point operations should behave the same on either index kind, while
+// range scans need the ordered one.
+#[test]
+fn index_kinds() -> Result<()> {
+    for kind in [IndexKind::Ordered, IndexKind::Hash] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder().index_kind(kind).open(temp_dir.path())?;
+        for i in 0..50 {
+            store.set(format!("key{}", i % 10), format!("value{i}"))?;
+        }
+        store.remove("key3".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value42".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.len(), 9);
+
+        store.compact_from_scan()?;
+        drop(store);
+        let store = KvStore::builder().index_kind(kind).open(temp_dir.path())?;
+        assert_eq!(store.get("key9".to_owned())?, Some("value49".to_owned()));
+        let keys: Vec<String> = store.iter()?.map(|pair| pair.map(|(k, _)| k)).collect::<Result<_>>()?;
+        assert_eq!(keys.first().map(String::as_str), Some("key0"));
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let range = store.get_range(std::ops::Bound::Included("key1"), std::ops::Bound::Excluded("key3"));
+        match kind {
+            IndexKind::Ordered => assert_eq!(range?.len(), 2),
+            IndexKind::Hash => {
+                assert!(matches!(range, Err(KvError::UnorderedIndexError)));
+                assert!(matches!(store.list_page(None, 5), Err(KvError::UnorderedIndexError)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Guards from `lock_key` should exclude each other for the same key only.
+#[test]
+fn lock_key() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?);
+    let inside = Arc::new(AtomicUsize::new(0));
+
+    let contenders: Vec<_> = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            let inside = inside.clone();
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    let _guard = store.lock_key("shared");
+                    assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+                    thread::sleep(Duration::from_millis(5));
+                    inside.fetch_sub(1, Ordering::SeqCst);
+                }
+            })
+        })
+        .collect();
+
+    // another key is free while this thread also contends for "shared"
+    let held = store.lock_key("shared");
+    assert_eq!(inside.fetch_add(1, Ordering::SeqCst), 0);
+    let (done, finished) = mpsc::channel();
+    let other = {
+        let store = store.clone();
+        thread::spawn(move || {
+            drop(store.lock_key("free"));
+            done.send(()).unwrap();
+        })
+    };
+    finished.recv_timeout(Duration::from_secs(10)).expect("lock on another key blocked");
+    other.join().unwrap();
+    inside.fetch_sub(1, Ordering::SeqCst);
+    drop(held);
+
+    for contender in contenders {
+        contender.join().unwrap();
+    }
+    drop(store.lock_key("shared"));
+
+    Ok(())
+}
+
+// The CLI should use the store in `TOYCASK_DIR`, unless `--dir` names
+// another one.
+#[test]
+fn cli_store_dir_from_env() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cwd = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .env("TOYCASK_DIR", temp_dir.path())
+        .current_dir(&cwd)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env("TOYCASK_DIR", temp_dir.path())
+        .current_dir(&cwd)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--dir"])
+        .arg(other_dir.path())
+        .env("TOYCASK_DIR", temp_dir.path())
+        .current_dir(&cwd)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!cwd.path().join("store").exists());
+
+    Ok(())
+}
+
+// `verify_key` should accept an intact record and reject one damaged on
+// disk, without failing.
+#[test]
+fn verify_key() -> Result<()> {
+    use std::io::{Seek, SeekFrom};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.verify_key("key1")?);
+    assert!(store.verify_key("key2")?);
+    assert!(!store.verify_key("key3")?);
+
+    let offset = store.offset_of("key2").unwrap();
+    let mut file = std::fs::OpenOptions::new().write(true).open(temp_dir.path().join("store"))?;
+    file.seek(SeekFrom::Start(offset + 1))?;
+    file.write_all(b"#")?;
+    drop(file);
+
+    assert!(store.verify_key("key1")?);
+    assert!(!store.verify_key("key2")?);
+
+    Ok(())
+}
+
+// `drain` should hand back every live pair and leave the store empty, also
+// after reopening.
+#[test]
+fn drain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    store.set("a".to_owned(), "2".to_owned())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+    store.set("b".to_owned(), "4".to_owned())?;
+    store.remove("c".to_owned())?;
+
+    let before = store.iter()?;
+    let pairs = store.drain()?;
+    assert_eq!(pairs, vec![("a".to_owned(), "2".to_owned()), ("b".to_owned(), "4".to_owned())]);
+    assert_eq!(store.len(), 0);
+    assert_eq!(store.log_size(), 0);
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(before.count(), 2);
+
+    store.set("d".to_owned(), "5".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("d".to_owned())?, Some("5".to_owned()));
+
+    Ok(())
+}
+
+// `bulk_load` should keep the last value of a repeated key by default.
+#[test]
+fn bulk_load_last_wins() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let pairs = [("key1", "first"), ("key2", "value2"), ("key1", "second")]
+        .map(|(k, v)| (k.to_owned(), v.to_owned()));
+
+    assert_eq!(store.bulk_load(pairs)?, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("second".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("second".to_owned()));
+
+    Ok(())
+}
+
+// With `OnDuplicate::Error`, a repeated key should fail the whole load.
+#[test]
+fn bulk_load_duplicate_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().on_duplicate(OnDuplicate::Error).open(temp_dir.path())?;
+    let pairs = [("key1", "first"), ("key2", "value2"), ("key1", "second")]
+        .map(|(k, v)| (k.to_owned(), v.to_owned()));
+
+    assert!(matches!(store.bulk_load(pairs), Err(KvError::DuplicateKeyError(key)) if key == "key1"));
+    assert_eq!(store.len(), 0);
+    assert_eq!(store.log_size(), 0);
+
+    Ok(())
+}
+
+// Lazy handles should only touch the log when read.
+#[test]
+fn get_lazy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+
+    let opens = store.log_opens();
+    let handles: Vec<_> = (0..20).map(|i| store.get_lazy(&format!("key{i}")).unwrap()).collect();
+    assert!(store.get_lazy("key20").is_none());
+    assert_eq!(store.log_opens(), opens);
+
+    for handle in handles.iter().step_by(5) {
+        let i = &handle.key()[3..];
+        assert_eq!(handle.read(&store)?, format!("value{i}"));
+    }
+    assert_eq!(store.log_opens(), opens + 4);
+
+    // handles outlive writes to their key
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    assert_eq!(handles[1].read(&store)?, "changed");
+    store.remove("key2".to_owned())?;
+    assert!(matches!(handles[2].read(&store), Err(KvError::KeyNotFoundError)));
+
+    Ok(())
+}
+
+// Values over `blob_threshold` should live in blob files that compaction
+// leaves in place and cleans up once unused.
+#[test]
+fn blob_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().blob_threshold(100).open(temp_dir.path())?;
+    let large = "x".repeat(10_000);
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    let blobs = temp_dir.path().join("blobs");
+    assert_eq!(std::fs::read_dir(&blobs)?.count(), 1);
+    assert!(store.log_size() < 500);
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    store.compact_from_scan()?;
+    assert_eq!(std::fs::read_dir(&blobs)?.count(), 1);
+    assert!(store.log_size() < 500);
+    drop(store);
+
+    let mut store = KvStore::builder().blob_threshold(100).open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    store.set("large".to_owned(), "y".repeat(200))?;
+    store.compact_from_scan()?;
+    assert_eq!(std::fs::read_dir(&blobs)?.count(), 1);
+    assert_eq!(store.get("large".to_owned())?, Some("y".repeat(200)));
+    store.remove("large".to_owned())?;
+    store.compact_from_scan()?;
+    assert_eq!(std::fs::read_dir(&blobs)?.count(), 0);
+
+    Ok(())
+}
+
+// An iterator should still read the blobs of its snapshot after compaction
+// or drain replaced them, and they should go once it is dropped.
+#[test]
+fn blob_snapshot_outlives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blobs = temp_dir.path().join("blobs");
+    let mut store = KvStore::builder().blob_threshold(10).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "a".repeat(100))?;
+    store.set("key2".to_owned(), "b".repeat(100))?;
+
+    let mut iter = store.iter()?;
+    store.set("key1".to_owned(), "c".repeat(100))?;
+    store.compact()?;
+    assert_eq!(iter.next().transpose()?, Some(("key1".to_owned(), "a".repeat(100))));
+    drop(iter);
+    store.compact()?;
+    assert_eq!(std::fs::read_dir(&blobs)?.count(), 2);
+
+    let iter = store.iter()?;
+    store.drain()?;
+    let pairs = iter.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, vec![("key1".to_owned(), "c".repeat(100)), ("key2".to_owned(), "b".repeat(100))]);
+    store.compact()?;
+    assert_eq!(std::fs::read_dir(&blobs)?.count(), 0);
+
+    Ok(())
+}
+
+// Records of blob values should replicate with their values inline, and
+// records that only name a blob should be refused.
+#[test]
+fn replicate_blob_values() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut leader = KvStore::builder().blob_threshold(10).compression(Compression::Gzip).open(leader_dir.path())?;
+    let large = "large value ".repeat(20);
+    leader.set("large".to_owned(), large.clone())?;
+    leader.set("small".to_owned(), "value".to_owned())?;
+
+    let changes = leader.changes_since(0)?;
+    let mut follower = KvStore::open(follower_dir.path())?;
+    follower.apply_raw(&changes)?;
+    assert_eq!(follower.get("large".to_owned())?, Some(large));
+    assert_eq!(follower.get("small".to_owned())?, Some("value".to_owned()));
+    assert!(!follower_dir.path().join("blobs").exists());
+
+    let raw = std::fs::read_to_string(leader_dir.path().join("store"))?;
+    let blob_record: Op = serde_json::from_str(raw.lines().next().unwrap())?;
+    assert!(matches!(follower.apply_raw(&[(0, blob_record)]), Err(KvError::InvalidCommandError)));
+
+    Ok(())
+}
+
+// `stale_versions` should count the superseded sets of a key.
+#[test]
+fn stale_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set("churned".to_owned(), format!("value{i}"))?;
+    }
+    store.set("stable".to_owned(), "value".to_owned())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    assert_eq!(store.stale_versions("churned")?, 4);
+    assert_eq!(store.stale_versions("stable")?, 0);
+    assert_eq!(store.stale_versions("removed")?, 1);
+    assert_eq!(store.stale_versions("missing")?, 0);
+
+    store.compact_from_scan()?;
+    assert_eq!(store.stale_versions("churned")?, 0);
+
+    Ok(())
+}
+
+// Adding a shard under a consistent hash router should move only about
+// its share of keys, and removing a shard should move only its own keys
+// and lose none.
+#[test]
+fn consistent_hash_resharding() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dirs: Vec<_> = (0..3).map(|i| temp_dir.path().join(format!("shard{i}"))).collect();
+    let mut store = ShardedKvStore::open(dirs, ConsistentHashRouter::new(3))?;
+    for i in 0..600 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    for n in 0..3 {
+        assert!(store.shard(n).unwrap().len() > 50);
+    }
+
+    let moved = store.add_shard(temp_dir.path().join("shard3"))?;
+    assert!(moved > 0 && moved < 600 / 2, "moved {moved} of 600 keys");
+    assert_eq!(store.shard(3).unwrap().len(), moved);
+    assert_eq!(store.len(), 600);
+    for i in 0..600 {
+        assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+
+    // the shard each key lives in, by shard id
+    let owners = |store: &ShardedKvStore<ConsistentHashRouter>| -> Vec<usize> {
+        (0..600).map(|i| store.router().shard_ids()[store.shard_of(&format!("key{i}"))]).collect()
+    };
+    let before = owners(&store);
+    let on_removed = store.shard(1).unwrap().len();
+    let removed = store.remove_shard(1)?.unwrap();
+    assert!(removed.is_empty());
+    assert_eq!(store.len(), 600);
+    assert_eq!(store.router().shard_ids(), [0, 2, 3]);
+    let moved = before.iter().zip(owners(&store)).filter(|(before, after)| **before != *after).count();
+    assert_eq!(moved, on_removed);
+    for i in 0..600 {
+        assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+    assert!(store.remove_shard(3)?.is_none());
+    drop(removed);
+    drop(store);
+
+    // a fresh router over the remaining shard ids routes the same way
+    let dirs: Vec<_> = [0, 2, 3].iter().map(|i| temp_dir.path().join(format!("shard{i}"))).collect();
+    let store = ShardedKvStore::open(dirs, ConsistentHashRouter::with_ids([0, 2, 3]))?;
+    for i in 0..600 {
+        assert_eq!(store.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+
+    Ok(())
+}
+
+// An explicit `compact` should report the records and bytes it dropped.
+#[test]
+fn compact_summary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{i}"))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let summary = store.compact()?;
+    assert_eq!(summary.records_before, 101);
+    assert_eq!(summary.records_after, 9);
+    assert!(summary.records_after <= store.len());
+    assert!(summary.bytes_after < summary.bytes_before);
+    assert_eq!(summary.bytes_after, store.log_size());
+    assert!(summary.duration > Duration::ZERO);
+    assert_eq!(store.get("key5".to_owned())?, Some("value95".to_owned()));
+
+    // compacting a compacted log changes nothing
+    let again = store.compact()?;
+    assert_eq!(again.records_before, 9);
+    assert_eq!(again.bytes_before, again.bytes_after);
+
+    Ok(())
+}
+
+// `key!` should build keys that round-trip through the store and
+// `parse_key`, and never collide however components are chosen.
+#[test]
+fn composite_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let id = 42;
+    store.set(key!("user", id), "alice".to_owned())?;
+    assert_eq!(store.get(key!("user", 42))?, Some("alice".to_owned()));
+    assert_eq!(store.get(key!("order", 42))?, None);
+
+    let cases: Vec<(&str, Vec<&str>)> = vec![
+        ("user", vec!["a:b"]),
+        ("user", vec!["a", "b"]),
+        ("user", vec!["a\\", "b"]),
+        ("user", vec!["a\\:b"]),
+        ("user", vec!["a", "", "b"]),
+        ("user", vec![]),
+        ("user", vec![""]),
+        ("order", vec!["a:b"]),
+        ("order", vec!["user", "a", "b"]),
+    ];
+    for (i, (prefix, components)) in cases.iter().enumerate() {
+        let key = kvs::composite_key(prefix, components);
+        let parsed = parse_key(&key).unwrap();
+        assert_eq!(parsed.0, *prefix);
+        assert_eq!(parsed.1, *components);
+        store.set(key, format!("value{i}"))?;
+    }
+    for (i, (prefix, components)) in cases.iter().enumerate() {
+        assert_eq!(store.get(kvs::composite_key(prefix, components))?, Some(format!("value{i}")));
+    }
+
+    Ok(())
+}
+
+// With `flush_every`, writes should reach the file only once a batch of
+// that many is complete.
+#[test]
+fn flush_every() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().flush_every(10).open(temp_dir.path())?;
+    for i in 0..9 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    assert!(KvStore::open_read_only(temp_dir.path())?.is_empty());
+    assert_eq!(std::fs::metadata(temp_dir.path().join("store"))?.len(), 0);
+
+    store.set("key9".to_owned(), "value9".to_owned())?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.len(), 10);
+    for i in 0..10 {
+        assert_eq!(reader.get(format!("key{i}"))?, Some(format!("value{i}")));
+    }
+
+    Ok(())
+}
+
+// `dead_bytes` should grow by the size of each superseded record and drop
+// after compaction to the versions it keeps.
+#[test]
+fn dead_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.dead_bytes(), 0);
+
+    let first_record = store.offset_of("key2").unwrap();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.dead_bytes(), first_record);
+    assert_eq!(store.stats().dead_bytes, first_record);
+
+    // a removal kills both the set and its tombstone
+    let before_remove = store.log_size();
+    store.remove("key2".to_owned())?;
+    let tombstone = store.log_size() - before_remove;
+    let second_record = store.offset_of("key1").unwrap() - first_record;
+    assert_eq!(store.dead_bytes(), first_record + second_record + tombstone);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.dead_bytes(), first_record + second_record + tombstone);
+    store.compact()?;
+    assert_eq!(store.dead_bytes(), 0);
+    assert_eq!(store.stats().dead_bytes, 0);
+    drop(store);
+
+    // versions kept through compaction are dead, unchanged by reopening
+    let versioned_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().keep_versions(3).open(versioned_dir.path())?;
+    for i in 0..3 {
+        store.set("key1".to_owned(), format!("value{i}"))?;
+    }
+    store.compact()?;
+    let latest = store.key_disk_size("key1")?.unwrap();
+    assert_eq!(store.dead_bytes(), store.log_size() - latest);
+    assert!(store.dead_bytes() > 0);
+    let dead = store.dead_bytes();
+    drop(store);
+    assert_eq!(KvStore::builder().keep_versions(3).open(versioned_dir.path())?.dead_bytes(), dead);
+
+    Ok(())
+}
+
+// A read-only handle should notice the writer swapping in a compacted log,
+// and a shared one should move to it on its own.
+#[test]
+fn reader_follows_compacted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+
+    let mut plain = KvStore::open_read_only(temp_dir.path())?;
+    let shared = SharedKvStore::new(KvStore::open_read_only(temp_dir.path())?);
+    assert_eq!(shared.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!plain.refresh()?);
+
+    writer.set("key1".to_owned(), "value3".to_owned())?;
+    writer.remove("key2".to_owned())?;
+    writer.compact()?;
+    writer.set("key3".to_owned(), "value4".to_owned())?;
+
+    assert!(matches!(plain.get("key1".to_owned()), Err(KvError::LogReplacedError)));
+    assert!(plain.refresh()?);
+    assert_eq!(plain.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    assert_eq!(shared.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(shared.get("key2".to_owned())?, None);
+    assert_eq!(shared.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// key_disk_size should match the length of each key's live record, before
+// and after reopening, and be None for a missing key.
+#[test]
+fn key_disk_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "a much longer value2".to_owned())?;
+    store.set("key3".to_owned(), String::new())?;
+    store.set("key1".to_owned(), "value11".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.remove("key4".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let log = std::fs::read(temp_dir.path().join("store"))?;
+        for key in ["key1", "key2", "key3"] {
+            let offset = store.offset_of(key).unwrap() as usize;
+            let record_len = log[offset..].iter().position(|b| *b == b'\n').unwrap() + 1;
+            assert_eq!(store.key_disk_size(key)?, Some(record_len as u64));
+        }
+        assert_eq!(store.key_disk_size("key4")?, None);
+        assert_eq!(store.key_disk_size("key5")?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Compaction should copy each live record byte for byte, so the new log is
+// exactly the old live records in key order and every value reads the same.
+#[test]
+fn compaction_copies_live_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().compression(Compression::Gzip).open(temp_dir.path())?;
+    for round in 0..3 {
+        for key_id in 0..20 {
+            store.set(format!("key{key_id:02}"), format!("value{key_id}-{round}").repeat(key_id + 1))?;
+        }
+    }
+    store.set("plain".to_owned(), "árvíztűrő".to_owned())?;
+    store.remove("key07".to_owned())?;
+
+    let old_log = std::fs::read(temp_dir.path().join("store"))?;
+    let mut keys: Vec<String> = store.index_entries().map(|(k, _)| k.clone()).collect();
+    keys.sort();
+    let mut expected_log = Vec::new();
+    let mut values = Vec::new();
+    for key in &keys {
+        let offset = store.offset_of(key).unwrap() as usize;
+        let len = store.key_disk_size(key)?.unwrap() as usize;
+        expected_log.extend_from_slice(&old_log[offset..offset + len]);
+        values.push(store.get(key.clone())?);
+    }
+
+    store.compact()?;
+    assert_eq!(std::fs::read(temp_dir.path().join("store"))?, expected_log);
+    for (key, value) in keys.iter().zip(&values) {
+        assert_eq!(&store.get(key.clone())?, value);
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in keys.iter().zip(&values) {
+        assert_eq!(&store.get(key.clone())?, value);
+    }
+
+    Ok(())
+}
+
+// keys_with_value should return exactly the keys holding a value, follow
+// overwrites and removals, and be rebuilt on reopen.
+#[test]
+fn keys_with_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder().value_index(true).compression(Compression::Gzip).open(temp_dir.path())?;
+    for key in ["key1", "key2", "key3"] {
+        store.set(key.to_owned(), "same".to_owned())?;
+    }
+    store.set("key4".to_owned(), "other".to_owned())?;
+    assert_eq!(store.keys_with_value("same"), vec!["key1", "key2", "key3"]);
+    assert_eq!(store.keys_with_value("other"), vec!["key4"]);
+    assert!(store.keys_with_value("missing").is_empty());
+
+    store.set("key2".to_owned(), "other".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.keys_with_value("same"), vec!["key1"]);
+    assert_eq!(store.keys_with_value("other"), vec!["key2", "key4"]);
+
+    let mut writer = store.begin_value("key1".to_owned())?;
+    writer.write_all(b"oth")?;
+    writer.write_all(b"er")?;
+    writer.commit()?;
+    assert!(store.keys_with_value("same").is_empty());
+    assert_eq!(store.keys_with_value("other"), vec!["key1", "key2", "key4"]);
+    drop(store);
+
+    let mut store = KvStore::builder().value_index(true).open(temp_dir.path())?;
+    assert!(store.keys_with_value("same").is_empty());
+    assert_eq!(store.keys_with_value("other"), vec!["key1", "key2", "key4"]);
+    store.compact()?;
+    assert_eq!(store.keys_with_value("other"), vec!["key1", "key2", "key4"]);
+    drop(store);
+
+    // without the flag nothing is tracked
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.keys_with_value("other").is_empty());
+
+    Ok(())
+}